@@ -0,0 +1,26 @@
+use opentelemetry::{global, KeyValue};
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let instrumentation_key =
+        env::var("INSTRUMENTATION_KEY").expect("env var INSTRUMENTATION_KEY should exist");
+
+    let controller = opentelemetry_application_insights::new_metrics_pipeline(instrumentation_key)
+        .with_client(reqwest::Client::new())
+        .install(opentelemetry::runtime::Tokio);
+
+    let meter = global::meter("example");
+    let requests = meter.u64_counter("requests").init();
+    let duration = meter.f64_value_recorder("request_duration").init();
+    for i in 0..10 {
+        requests.add(1, &[KeyValue::new("route", "/")]);
+        duration.record(i as f64, &[KeyValue::new("route", "/")]);
+    }
+
+    // Dropping the controller exports the remaining metrics.
+    drop(controller);
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+}
@@ -0,0 +1,470 @@
+use crate::{http_client::BoxError, HttpClient};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{Request, Response};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Scope of tokens used to send telemetry to Application Insights.
+pub(crate) const INGESTION_SCOPE: &str = "https://monitor.azure.com//.default";
+
+/// Tokens are refreshed this long before they expire, so they don't expire while a request is in
+/// flight.
+const REFRESH_BEFORE_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+const IMDS_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+const IMDS_API_VERSION: &str = "2018-02-01";
+const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+
+/// An Azure Active Directory access token.
+#[derive(Clone)]
+pub struct AccessToken {
+    /// The bearer token.
+    pub token: String,
+
+    /// Time at which the token expires.
+    pub expires_on: SystemTime,
+}
+
+impl fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("token", &"<redacted>")
+            .field("expires_on", &self.expires_on)
+            .finish()
+    }
+}
+
+/// Source of Azure Active Directory access tokens, which are used to authenticate requests to
+/// Application Insights.
+///
+/// This crate comes with implementations for managed identities
+/// ([`ManagedIdentityCredential`]), service principals ([`ClientSecretCredential`]) and fixed
+/// tokens ([`StaticTokenCredential`]). Implement this trait to bring tokens from anywhere else.
+///
+/// The exporter caches tokens and only asks for a new one shortly before the current one expires
+/// or if Application Insights rejects it.
+#[async_trait]
+pub trait TokenCredential: fmt::Debug + Send + Sync {
+    /// Get an access token for the given scope.
+    async fn get_token(&self, scope: &str) -> Result<AccessToken, BoxError>;
+}
+
+/// Credential, which always returns the same token. Mostly useful for tests.
+#[derive(Clone)]
+pub struct StaticTokenCredential {
+    token: String,
+}
+
+impl StaticTokenCredential {
+    /// Create a new credential, which always returns the given token.
+    pub fn new<T: Into<String>>(token: T) -> Self {
+        Self {
+            token: token.into(),
+        }
+    }
+}
+
+impl fmt::Debug for StaticTokenCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticTokenCredential")
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
+#[async_trait]
+impl TokenCredential for StaticTokenCredential {
+    async fn get_token(&self, _scope: &str) -> Result<AccessToken, BoxError> {
+        Ok(AccessToken {
+            token: self.token.clone(),
+            expires_on: SystemTime::now() + Duration::from_secs(365 * 24 * 60 * 60),
+        })
+    }
+}
+
+/// Credential, which gets tokens for the managed identity of an Azure resource (e.g. a virtual
+/// machine) from the [Azure Instance Metadata Service].
+///
+/// [Azure Instance Metadata Service]: https://docs.microsoft.com/en-us/azure/active-directory/managed-identities-azure-resources/how-to-use-vm-token
+#[derive(Debug)]
+pub struct ManagedIdentityCredential<C> {
+    client: C,
+    client_id: Option<String>,
+}
+
+impl<C> ManagedIdentityCredential<C> {
+    /// Create a new credential for the system assigned managed identity. The given HTTP client is
+    /// used to request tokens.
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            client_id: None,
+        }
+    }
+
+    /// Use the user assigned managed identity with the given client id instead of the system
+    /// assigned one.
+    pub fn with_client_id<T: Into<String>>(mut self, client_id: T) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+}
+
+#[async_trait]
+impl<C: HttpClient> TokenCredential for ManagedIdentityCredential<C> {
+    async fn get_token(&self, scope: &str) -> Result<AccessToken, BoxError> {
+        // The metadata service expects a resource instead of a scope.
+        let resource = scope.strip_suffix("/.default").unwrap_or(scope);
+        let mut uri = format!(
+            "{}?api-version={}&resource={}",
+            IMDS_ENDPOINT,
+            IMDS_API_VERSION,
+            encode(resource)
+        );
+        if let Some(client_id) = &self.client_id {
+            uri.push_str(&format!("&client_id={}", encode(client_id)));
+        }
+
+        let request = Request::get(uri)
+            .header("Metadata", "true")
+            .body(Vec::new())?;
+        parse_token_response(self.client.send(request).await?)
+    }
+}
+
+/// Credential, which gets tokens for a service principal using a client secret.
+pub struct ClientSecretCredential<C> {
+    client: C,
+    authority_host: String,
+    tenant_id: String,
+    client_id: String,
+    client_secret: String,
+}
+
+impl<C> ClientSecretCredential<C> {
+    /// Create a new credential for the service principal with the given client id in the given
+    /// tenant. The given HTTP client is used to request tokens.
+    pub fn new<T, I, S>(client: C, tenant_id: T, client_id: I, client_secret: S) -> Self
+    where
+        T: Into<String>,
+        I: Into<String>,
+        S: Into<String>,
+    {
+        Self {
+            client,
+            authority_host: DEFAULT_AUTHORITY_HOST.into(),
+            tenant_id: tenant_id.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+        }
+    }
+
+    /// Set the Azure Active Directory authority, e.g. for sovereign clouds.
+    ///
+    /// Default: https://login.microsoftonline.com
+    pub fn with_authority_host(mut self, authority_host: &str) -> Self {
+        self.authority_host = authority_host.trim_end_matches('/').into();
+        self
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for ClientSecretCredential<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientSecretCredential")
+            .field("client", &self.client)
+            .field("authority_host", &self.authority_host)
+            .field("tenant_id", &self.tenant_id)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<C: HttpClient> TokenCredential for ClientSecretCredential<C> {
+    async fn get_token(&self, scope: &str) -> Result<AccessToken, BoxError> {
+        let uri = format!(
+            "{}/{}/oauth2/v2.0/token",
+            self.authority_host,
+            encode(&self.tenant_id)
+        );
+        let body = format!(
+            "grant_type=client_credentials&client_id={}&client_secret={}&scope={}",
+            encode(&self.client_id),
+            encode(&self.client_secret),
+            encode(scope)
+        );
+        let request = Request::post(uri)
+            .header(
+                http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(body.into_bytes())?;
+        parse_token_response(self.client.send(request).await?)
+    }
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_on: Option<NumberOrString>,
+    expires_in: Option<NumberOrString>,
+}
+
+/// The metadata service sends numbers as strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(u64),
+    String(String),
+}
+
+impl NumberOrString {
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            NumberOrString::Number(number) => Some(*number),
+            NumberOrString::String(string) => string.parse().ok(),
+        }
+    }
+}
+
+fn parse_token_response(response: Response<Bytes>) -> Result<AccessToken, BoxError> {
+    if !response.status().is_success() {
+        return Err(format!(
+            "token request failed with status {}: {}",
+            response.status().as_u16(),
+            String::from_utf8_lossy(response.body())
+        )
+        .into());
+    }
+
+    let response: TokenResponse = serde_json::from_slice(response.body())?;
+    let expires_on = if let Some(expires_on) = response.expires_on.and_then(|x| x.as_u64()) {
+        UNIX_EPOCH + Duration::from_secs(expires_on)
+    } else if let Some(expires_in) = response.expires_in.and_then(|x| x.as_u64()) {
+        SystemTime::now() + Duration::from_secs(expires_in)
+    } else {
+        return Err("token response does not contain an expiry".into());
+    };
+
+    Ok(AccessToken {
+        token: response.access_token,
+        expires_on,
+    })
+}
+
+/// Caches the tokens of a credential and refreshes them shortly before they expire.
+#[derive(Debug)]
+pub(crate) struct CachedCredential {
+    credential: Arc<dyn TokenCredential>,
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl Clone for CachedCredential {
+    fn clone(&self) -> Self {
+        Self {
+            credential: Arc::clone(&self.credential),
+            token: Mutex::new(self.token.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl CachedCredential {
+    pub(crate) fn new(credential: Box<dyn TokenCredential>) -> Self {
+        Self {
+            credential: credential.into(),
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Returns a token for sending telemetry, which is valid for at least a few more minutes.
+    pub(crate) async fn token(&self) -> Result<String, BoxError> {
+        if let Some(token) = self.token.lock().unwrap().as_ref() {
+            if token.expires_on > SystemTime::now() + REFRESH_BEFORE_EXPIRY {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let token = self.credential.get_token(INGESTION_SCOPE).await?;
+        let result = token.token.clone();
+        *self.token.lock().unwrap() = Some(token);
+        Ok(result)
+    }
+
+    /// Forgets the cached token, e.g. because Application Insights rejected it.
+    pub(crate) fn invalidate(&self) {
+        *self.token.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Stand-in for a token endpoint, which responds with the given response.
+    #[derive(Debug)]
+    struct TokenEndpoint {
+        status: u16,
+        body: serde_json::Value,
+        requests: Mutex<Vec<Request<Vec<u8>>>>,
+    }
+
+    impl TokenEndpoint {
+        fn new(status: u16, body: serde_json::Value) -> Self {
+            Self {
+                status,
+                body,
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn request(&self) -> Request<Vec<u8>> {
+            self.requests.lock().unwrap().pop().unwrap()
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for TokenEndpoint {
+        async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, BoxError> {
+            self.requests.lock().unwrap().push(request);
+            Ok(Response::builder()
+                .status(self.status)
+                .body(self.body.to_string().into())?)
+        }
+    }
+
+    /// Credential, which returns `token-0`, `token-1`, ... with the given lifetime.
+    #[derive(Debug)]
+    pub(crate) struct CountingCredential {
+        lifetime: Duration,
+        calls: AtomicUsize,
+    }
+
+    impl CountingCredential {
+        pub(crate) fn new(lifetime: Duration) -> Self {
+            Self {
+                lifetime,
+                calls: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TokenCredential for CountingCredential {
+        async fn get_token(&self, scope: &str) -> Result<AccessToken, BoxError> {
+            assert_eq!(INGESTION_SCOPE, scope);
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(AccessToken {
+                token: format!("token-{}", call),
+                expires_on: SystemTime::now() + self.lifetime,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn managed_identity() {
+        let endpoint = TokenEndpoint::new(
+            200,
+            serde_json::json!({
+                "access_token": "secret",
+                "expires_on": "1586984735",
+                "expires_in": "3599",
+                "token_type": "Bearer",
+            }),
+        );
+        let credential = ManagedIdentityCredential::new(endpoint).with_client_id("my-client");
+
+        let token = credential.get_token(INGESTION_SCOPE).await.unwrap();
+        assert_eq!("secret", token.token);
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1586984735),
+            token.expires_on
+        );
+
+        let request = credential.client.request();
+        assert_eq!(http::Method::GET, request.method());
+        assert_eq!("true", request.headers()["Metadata"]);
+        assert_eq!(
+            "http://169.254.169.254/metadata/identity/oauth2/token?api-version=2018-02-01&resource=https%3A%2F%2Fmonitor%2Eazure%2Ecom%2F&client_id=my%2Dclient",
+            request.uri().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn client_secret() {
+        let endpoint = TokenEndpoint::new(
+            200,
+            serde_json::json!({
+                "access_token": "secret",
+                "expires_in": 3599,
+                "token_type": "Bearer",
+            }),
+        );
+        let credential = ClientSecretCredential::new(endpoint, "tenant", "client", "p&ss")
+            .with_authority_host("https://login.microsoftonline.us/");
+
+        let before = SystemTime::now();
+        let token = credential.get_token(INGESTION_SCOPE).await.unwrap();
+        assert_eq!("secret", token.token);
+        assert!(token.expires_on >= before + Duration::from_secs(3599));
+
+        let request = credential.client.request();
+        assert_eq!(http::Method::POST, request.method());
+        assert_eq!(
+            "https://login.microsoftonline.us/tenant/oauth2/v2.0/token",
+            request.uri().to_string()
+        );
+        assert_eq!(
+            "grant_type=client_credentials&client_id=client&client_secret=p%26ss&scope=https%3A%2F%2Fmonitor%2Eazure%2Ecom%2F%2F%2Edefault",
+            String::from_utf8(request.into_body()).unwrap()
+        );
+        assert!(!format!("{:?}", credential).contains("p&ss"));
+    }
+
+    #[tokio::test]
+    async fn token_endpoint_errors() {
+        let endpoint = TokenEndpoint::new(400, serde_json::json!({ "error": "invalid_client" }));
+        let credential = ClientSecretCredential::new(endpoint, "tenant", "client", "secret");
+        let error = credential
+            .get_token(INGESTION_SCOPE)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("invalid_client"), "{}", error);
+
+        let endpoint = TokenEndpoint::new(200, serde_json::json!({ "access_token": "secret" }));
+        let credential = ManagedIdentityCredential::new(endpoint);
+        assert!(credential.get_token(INGESTION_SCOPE).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn caches_tokens() {
+        let cached = CachedCredential::new(Box::new(CountingCredential::new(Duration::from_secs(
+            60 * 60,
+        ))));
+        assert_eq!("token-0", cached.token().await.unwrap());
+        assert_eq!("token-0", cached.token().await.unwrap());
+
+        cached.invalidate();
+        assert_eq!("token-1", cached.token().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refreshes_tokens_before_they_expire() {
+        let cached =
+            CachedCredential::new(Box::new(CountingCredential::new(Duration::from_secs(60))));
+        assert_eq!("token-0", cached.token().await.unwrap());
+        assert_eq!("token-1", cached.token().await.unwrap());
+    }
+}
@@ -0,0 +1,78 @@
+use opentelemetry::{
+    trace::{Span as _, StatusCode, Tracer},
+    Key,
+};
+use std::time::{Duration, SystemTime};
+
+/// Attribute, which marks a span as the result of an availability test. Spans with this attribute
+/// are sent as Availability telemetry. The value is the name of the location the test ran from.
+pub const AVAILABILITY_RUN_LOCATION: Key = Key::from_static_str("ai.availability.run_location");
+
+/// Result of an availability test, e.g. a health check.
+#[derive(Debug, Clone)]
+pub struct AvailabilityResult {
+    /// Name of the test.
+    pub name: String,
+
+    /// Name of the location the test ran from.
+    pub run_location: String,
+
+    /// Whether the test succeeded.
+    pub success: bool,
+
+    /// Diagnostic message for the result.
+    pub message: Option<String>,
+
+    /// Time at which the test started.
+    pub start_time: SystemTime,
+
+    /// Duration of the test.
+    pub duration: Duration,
+}
+
+/// Track the result of an availability test. The result is recorded as a span marked with
+/// [`AVAILABILITY_RUN_LOCATION`], which the exporter sends as Availability telemetry.
+///
+/// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+///
+/// [`reqwest`]: https://crates.io/crates/reqwest
+///
+/// ```no_run
+/// use opentelemetry_application_insights::{track_availability, AvailabilityResult};
+/// use std::time::{Instant, SystemTime};
+///
+/// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+///     .with_client(reqwest::blocking::Client::new())
+///     .install_simple();
+///
+/// let start_time = SystemTime::now();
+/// let start = Instant::now();
+/// let response = reqwest::blocking::get("http://localhost:8080/health");
+/// track_availability(
+///     &tracer,
+///     AvailabilityResult {
+///         name: "health".into(),
+///         run_location: "west-europe".into(),
+///         success: response.is_ok(),
+///         message: response.err().map(|err| err.to_string()),
+///         start_time,
+///         duration: start.elapsed(),
+///     },
+/// );
+/// ```
+pub fn track_availability<T: Tracer>(tracer: &T, result: AvailabilityResult) {
+    let mut builder = tracer
+        .span_builder(result.name)
+        .with_start_time(result.start_time)
+        .with_attributes(vec![AVAILABILITY_RUN_LOCATION.string(result.run_location)])
+        .with_status_code(if result.success {
+            StatusCode::Ok
+        } else {
+            StatusCode::Error
+        });
+    if let Some(message) = result.message {
+        builder = builder.with_status_message(message);
+    }
+    let mut span = builder.start(tracer);
+    span.end_with_timestamp(result.start_time + result.duration);
+}
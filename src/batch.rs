@@ -0,0 +1,155 @@
+use crate::{models::Envelope, uploader::Uploader, HttpClient, MAX_STORED_BATCHES_PER_EXPORT};
+use futures_channel::{mpsc, oneshot};
+use futures_timer::Delay;
+use futures_util::{future, stream, StreamExt as _};
+use opentelemetry::{global, runtime::Runtime, trace::TraceError};
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Maximum time `BatchSender::flush` waits for buffered telemetry to be uploaded.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limits of the buffer of a `BatchSender`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BatchConfig {
+    /// Maximum number of buffered items. Items sent while the buffer is full are dropped.
+    pub(crate) max_queue_size: usize,
+    /// Interval in which buffered items are uploaded.
+    pub(crate) scheduled_delay: Duration,
+    /// Number of buffered items, which triggers an upload before the scheduled delay passed.
+    pub(crate) max_export_batch_size: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_size: 2048,
+            scheduled_delay: Duration::from_secs(5),
+            max_export_batch_size: 512,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Message {
+    Envelope(Box<Envelope>),
+    Flush(oneshot::Sender<()>),
+    Tick,
+    Shutdown,
+}
+
+/// Buffers telemetry and uploads it in batches from a background task. The task uploads the
+/// remaining telemetry and stops when the sender is dropped.
+#[derive(Debug)]
+pub(crate) struct BatchSender {
+    sender: Mutex<mpsc::Sender<Message>>,
+}
+
+impl BatchSender {
+    pub(crate) fn spawn<C, R>(
+        client: Arc<C>,
+        uploader: Arc<Uploader>,
+        config: BatchConfig,
+        runtime: R,
+    ) -> Self
+    where
+        C: HttpClient + 'static,
+        R: Runtime,
+    {
+        let (sender, receiver) = mpsc::channel(config.max_queue_size);
+        let ticks = runtime
+            .interval(config.scheduled_delay)
+            .map(|_| Message::Tick);
+        let messages = stream::select(
+            receiver.chain(stream::once(future::ready(Message::Shutdown))),
+            ticks,
+        );
+        runtime.spawn(Box::pin(transmit(
+            client,
+            uploader,
+            messages,
+            config.max_export_batch_size,
+        )));
+
+        Self {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Buffers the envelope. It's dropped if the buffer is full.
+    pub(crate) fn send(&self, envelope: Envelope) {
+        self.send_message(Message::Envelope(Box::new(envelope)));
+    }
+
+    /// Uploads all buffered telemetry and blocks until it's done or `FLUSH_TIMEOUT` passed.
+    pub(crate) fn flush(&self) {
+        futures_executor::block_on(self.flush_async());
+    }
+
+    /// Uploads all buffered telemetry and waits until it's done or `FLUSH_TIMEOUT` passed.
+    pub(crate) async fn flush_async(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.send_message(Message::Flush(done)) {
+            future::select(wait, Delay::new(FLUSH_TIMEOUT)).await;
+        }
+    }
+
+    fn send_message(&self, message: Message) -> bool {
+        match self.sender.lock() {
+            Ok(mut sender) => sender.try_send(message).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Background task, which collects telemetry and uploads it in batches.
+async fn transmit<C: HttpClient>(
+    client: Arc<C>,
+    uploader: Arc<Uploader>,
+    messages: impl futures_util::Stream<Item = Message>,
+    max_export_batch_size: usize,
+) {
+    futures_util::pin_mut!(messages);
+    let mut batch = Vec::new();
+    while let Some(message) = messages.next().await {
+        let done = match message {
+            Message::Envelope(envelope) => {
+                batch.push(*envelope);
+                if batch.len() < max_export_batch_size {
+                    continue;
+                }
+                None
+            }
+            Message::Flush(done) => Some(done),
+            Message::Tick => None,
+            Message::Shutdown => break,
+        };
+
+        if !batch.is_empty() {
+            match uploader.send(&*client, mem::take(&mut batch)).await {
+                // Telemetry saved in the offline storage is sent after successful uploads.
+                Ok(()) => {
+                    if let Err(err) = uploader
+                        .send_stored(&*client, MAX_STORED_BATCHES_PER_EXPORT)
+                        .await
+                    {
+                        global::handle_error(TraceError::from(err));
+                    }
+                }
+                Err(err) => global::handle_error(TraceError::from(err)),
+            }
+        }
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+
+    if !batch.is_empty() {
+        if let Err(err) = uploader.send(&*client, batch).await {
+            global::handle_error(TraceError::from(err));
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use http::Uri;
+use std::{collections::HashMap, convert::TryInto, str::FromStr};
+
+const MAX_LENGTH: usize = 4096;
+const DEFAULT_INGESTION_ENDPOINT: &str = "https://dc.services.visualstudio.com";
+const DEFAULT_LIVE_ENDPOINT: &str = "https://rt.services.visualstudio.com";
+
+const KEY_AUTHORIZATION: &str = "authorization";
+const KEY_ENDPOINT_SUFFIX: &str = "endpointsuffix";
+const KEY_INGESTION_ENDPOINT: &str = "ingestionendpoint";
+const KEY_INSTRUMENTATION_KEY: &str = "instrumentationkey";
+const KEY_LIVE_ENDPOINT: &str = "liveendpoint";
+const KEY_LOCATION: &str = "location";
+
+/// Parsed Application Insights [connection string].
+///
+/// [connection string]: https://docs.microsoft.com/en-us/azure/azure-monitor/app/sdk-connection-string
+///
+/// Endpoints are determined as follows:
+///
+/// 1. An explicit `IngestionEndpoint` or `LiveEndpoint` is used as is.
+/// 2. Otherwise, if an `EndpointSuffix` is given, the endpoints are derived from it (e.g.
+///    `https://dc.applicationinsights.azure.cn` for the suffix `applicationinsights.azure.cn`).
+///    An optional `Location` is used as region prefix (e.g.
+///    `https://westus2.dc.applicationinsights.azure.com`).
+/// 3. Otherwise the global endpoints of the public Azure cloud are used.
+///
+/// ```
+/// use opentelemetry_application_insights::ConnectionString;
+///
+/// let connection_string: ConnectionString =
+///     "InstrumentationKey=0fdcec70-0ce5-4085-89d9-9ae8ead9af66;EndpointSuffix=ai.contoso.com"
+///         .parse()
+///         .unwrap();
+/// assert_eq!(
+///     "0fdcec70-0ce5-4085-89d9-9ae8ead9af66",
+///     connection_string.instrumentation_key()
+/// );
+/// assert_eq!(
+///     "https://dc.ai.contoso.com/",
+///     connection_string.ingestion_endpoint().to_string()
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionString {
+    instrumentation_key: String,
+    ingestion_endpoint: Uri,
+    live_endpoint: Uri,
+    aad_authorization: bool,
+}
+
+impl ConnectionString {
+    /// The instrumentation key.
+    pub fn instrumentation_key(&self) -> &str {
+        &self.instrumentation_key
+    }
+
+    /// Endpoint used to ingest telemetry.
+    pub fn ingestion_endpoint(&self) -> &Uri {
+        &self.ingestion_endpoint
+    }
+
+    /// Endpoint used for Live Metrics.
+    pub fn live_endpoint(&self) -> &Uri {
+        &self.live_endpoint
+    }
+
+    /// Whether the connection string specifies `Authorization=AAD`, i.e. the Application Insights
+    /// resource requires Azure Active Directory authentication.
+    pub fn aad_authorization(&self) -> bool {
+        self.aad_authorization
+    }
+
+    /// Endpoint used to send telemetry (`<ingestion endpoint>/v2/track`).
+    pub(crate) fn track_endpoint(&self) -> Uri {
+        format!(
+            "{}/v2/track",
+            self.ingestion_endpoint.to_string().trim_end_matches('/')
+        )
+        .try_into()
+        .expect("ingestion endpoint was validated during parsing")
+    }
+}
+
+impl FromStr for ConnectionString {
+    type Err = ConnectionStringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err(ConnectionStringError::Empty);
+        }
+        if s.len() > MAX_LENGTH {
+            return Err(ConnectionStringError::TooLong(MAX_LENGTH));
+        }
+
+        let mut values = HashMap::new();
+        for pair in s.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                    (key.trim(), value.trim())
+                }
+                _ => return Err(ConnectionStringError::InvalidPair(pair.to_string())),
+            };
+            if values.insert(key.to_lowercase(), value).is_some() {
+                return Err(ConnectionStringError::DuplicateKey(key.to_string()));
+            }
+        }
+
+        let instrumentation_key = values
+            .get(KEY_INSTRUMENTATION_KEY)
+            .ok_or(ConnectionStringError::MissingInstrumentationKey)?
+            .to_string();
+
+        let aad_authorization = match values.get(KEY_AUTHORIZATION) {
+            None => false,
+            Some(authorization) if authorization.eq_ignore_ascii_case("aad") => true,
+            Some(authorization) => {
+                return Err(ConnectionStringError::UnsupportedAuthorization(
+                    authorization.to_string(),
+                ))
+            }
+        };
+
+        let ingestion_endpoint = endpoint(&values, KEY_INGESTION_ENDPOINT, "dc")?
+            .unwrap_or_else(|| DEFAULT_INGESTION_ENDPOINT.to_string());
+        let live_endpoint = endpoint(&values, KEY_LIVE_ENDPOINT, "live")?
+            .unwrap_or_else(|| DEFAULT_LIVE_ENDPOINT.to_string());
+
+        Ok(Self {
+            instrumentation_key,
+            ingestion_endpoint: parse_endpoint(&ingestion_endpoint)?,
+            live_endpoint: parse_endpoint(&live_endpoint)?,
+            aad_authorization,
+        })
+    }
+}
+
+/// Returns the explicit endpoint or derives one from the endpoint suffix, if any.
+fn endpoint(
+    values: &HashMap<String, &str>,
+    key: &str,
+    prefix: &str,
+) -> Result<Option<String>, ConnectionStringError> {
+    if let Some(endpoint) = values.get(key) {
+        return Ok(Some(endpoint.to_string()));
+    }
+
+    let suffix = match values.get(KEY_ENDPOINT_SUFFIX) {
+        Some(suffix) => suffix.trim_start_matches('.'),
+        None => return Ok(None),
+    };
+    let suffix = suffix
+        .strip_prefix("https://")
+        .or_else(|| suffix.strip_prefix("http://"))
+        .unwrap_or(suffix)
+        .trim_end_matches('/');
+    if suffix.is_empty() {
+        return Err(ConnectionStringError::InvalidEndpoint(suffix.to_string()));
+    }
+
+    Ok(Some(match values.get(KEY_LOCATION) {
+        Some(location) => format!("https://{}.{}.{}", location, prefix, suffix),
+        None => format!("https://{}.{}", prefix, suffix),
+    }))
+}
+
+fn parse_endpoint(endpoint: &str) -> Result<Uri, ConnectionStringError> {
+    let with_scheme = if endpoint.contains("://") {
+        endpoint.to_string()
+    } else {
+        format!("https://{}", endpoint)
+    };
+    let uri: Uri = with_scheme
+        .parse()
+        .map_err(|_| ConnectionStringError::InvalidEndpoint(endpoint.to_string()))?;
+    match (uri.scheme_str(), uri.host()) {
+        (Some("https"), Some(_)) | (Some("http"), Some(_)) => Ok(uri),
+        _ => Err(ConnectionStringError::InvalidEndpoint(endpoint.to_string())),
+    }
+}
+
+/// Errors that occurred while parsing a connection string.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ConnectionStringError {
+    /// The connection string is empty.
+    #[error("connection string is empty")]
+    Empty,
+
+    /// The connection string is longer than the maximum length.
+    #[error("connection string exceeds the maximum length of {0} characters")]
+    TooLong(usize),
+
+    /// The connection string contains a segment, which is not a `key=value` pair.
+    #[error("connection string contains invalid segment {0:?}")]
+    InvalidPair(String),
+
+    /// The connection string contains the same key more than once.
+    #[error("connection string contains duplicate key {0:?}")]
+    DuplicateKey(String),
+
+    /// The connection string does not contain an `InstrumentationKey`.
+    #[error("connection string does not contain an InstrumentationKey")]
+    MissingInstrumentationKey,
+
+    /// The connection string contains an `Authorization` other than `AAD`.
+    #[error("connection string contains unsupported authorization {0:?}")]
+    UnsupportedAuthorization(String),
+
+    /// The connection string contains an endpoint or endpoint suffix, which is not a valid URL.
+    #[error("connection string contains invalid endpoint {0:?}")]
+    InvalidEndpoint(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("InstrumentationKey=key", "https://dc.services.visualstudio.com/", "https://rt.services.visualstudio.com/" ; "default")]
+    #[test_case("InstrumentationKey=key;EndpointSuffix=applicationinsights.azure.cn", "https://dc.applicationinsights.azure.cn/", "https://live.applicationinsights.azure.cn/" ; "suffix")]
+    #[test_case("InstrumentationKey=key;EndpointSuffix=ai.contoso.com;Location=westus2", "https://westus2.dc.ai.contoso.com/", "https://westus2.live.ai.contoso.com/" ; "suffix with location")]
+    #[test_case("InstrumentationKey=key;EndpointSuffix=ai.contoso.com;IngestionEndpoint=https://custom.com:444/", "https://custom.com:444/", "https://live.ai.contoso.com/" ; "explicit ingestion endpoint overrides suffix")]
+    #[test_case("InstrumentationKey=key;IngestionEndpoint=https://westeurope-5.in.applicationinsights.azure.com/;LiveEndpoint=https://westeurope.livediagnostics.monitor.azure.com/", "https://westeurope-5.in.applicationinsights.azure.com/", "https://westeurope.livediagnostics.monitor.azure.com/" ; "explicit endpoints")]
+    #[test_case(" instrumentationkey = key ; ingestionendpoint = localhost:8080 ; ", "https://localhost:8080/", "https://rt.services.visualstudio.com/" ; "case insensitive keys, whitespace and missing scheme")]
+    fn endpoints(connection_string: &str, ingestion: &str, live: &str) {
+        let connection_string: ConnectionString = connection_string.parse().unwrap();
+        assert_eq!("key", connection_string.instrumentation_key());
+        assert_eq!(
+            ingestion,
+            connection_string.ingestion_endpoint().to_string()
+        );
+        assert_eq!(live, connection_string.live_endpoint().to_string());
+    }
+
+    #[test]
+    fn track_endpoint() {
+        let connection_string: ConnectionString =
+            "InstrumentationKey=key;IngestionEndpoint=https://westus2-0.in.applicationinsights.azure.com/"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            "https://westus2-0.in.applicationinsights.azure.com/v2/track",
+            connection_string.track_endpoint().to_string()
+        );
+    }
+
+    #[test_case("InstrumentationKey=key", false ; "none")]
+    #[test_case("InstrumentationKey=key;Authorization=AAD", true ; "aad")]
+    #[test_case("InstrumentationKey=key;Authorization=aad", true ; "aad lowercase")]
+    fn authorization(connection_string: &str, expected: bool) {
+        let connection_string: ConnectionString = connection_string.parse().unwrap();
+        assert_eq!(expected, connection_string.aad_authorization());
+    }
+
+    #[test_case("", ConnectionStringError::Empty ; "empty")]
+    #[test_case(" ; ", ConnectionStringError::MissingInstrumentationKey ; "only separators")]
+    #[test_case("EndpointSuffix=ai.contoso.com", ConnectionStringError::MissingInstrumentationKey ; "missing key")]
+    #[test_case("InstrumentationKey", ConnectionStringError::InvalidPair("InstrumentationKey".into()) ; "no value")]
+    #[test_case("InstrumentationKey=", ConnectionStringError::InvalidPair("InstrumentationKey=".into()) ; "empty value")]
+    #[test_case("InstrumentationKey=a;instrumentationkey=b", ConnectionStringError::DuplicateKey("instrumentationkey".into()) ; "duplicate key")]
+    #[test_case("InstrumentationKey=key;Authorization=Basic", ConnectionStringError::UnsupportedAuthorization("Basic".into()) ; "unsupported authorization")]
+    #[test_case("InstrumentationKey=key;IngestionEndpoint=ftp://example.com", ConnectionStringError::InvalidEndpoint("ftp://example.com".into()) ; "invalid scheme")]
+    #[test_case("InstrumentationKey=key;IngestionEndpoint=https://exa mple.com", ConnectionStringError::InvalidEndpoint("https://exa mple.com".into()) ; "invalid uri")]
+    fn invalid(connection_string: &str, expected: ConnectionStringError) {
+        assert_eq!(Err(expected), connection_string.parse::<ConnectionString>());
+    }
+
+    #[test]
+    fn too_long() {
+        let connection_string = format!("InstrumentationKey={}", "k".repeat(MAX_LENGTH));
+        assert_eq!(
+            Err(ConnectionStringError::TooLong(MAX_LENGTH)),
+            connection_string.parse::<ConnectionString>()
+        );
+    }
+}
@@ -1,11 +1,15 @@
-use crate::models::Properties;
+use crate::{
+    models::{Measurements, Properties, SeverityLevel},
+    SAMPLE_RATE,
+};
 use chrono::{DateTime, SecondsFormat, Utc};
 use opentelemetry::{
     sdk::{trace::EvictedHashMap, Resource},
     trace::{SpanId, TraceId},
+    Key, Value,
 };
-use std::time::{Duration, SystemTime};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 pub(crate) fn trace_id_to_string(trace_id: TraceId) -> String {
     format!("{:032x}", trace_id.to_u128())
@@ -38,6 +42,7 @@ pub(crate) fn attrs_to_properties(
 ) -> Option<Properties> {
     let properties = attributes
         .iter()
+        .filter(|(k, _)| **k != SAMPLE_RATE)
         .map(|(k, v)| (k.as_str().into(), v.into()));
 
     if let Some(resource) = resource {
@@ -48,18 +53,90 @@ pub(crate) fn attrs_to_properties(
         )
         .filter(|x: &Properties| !x.is_empty())
     } else {
-        Some(
-            properties.collect(),
-        )
-        .filter(|x: &Properties| !x.is_empty())
+        Some(properties.collect()).filter(|x: &Properties| !x.is_empty())
+    }
+}
+
+/// Determines which numeric attributes are sent as custom measurements instead of custom
+/// properties. Custom measurements can be charted and aggregated in Application Insights.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub enum MeasurementPolicy {
+    /// Attributes with `i64` and `f64` values become custom measurements.
+    Numeric,
+    /// Attributes with `i64` and `f64` values become custom measurements, except the attributes
+    /// with the given keys.
+    NumericExcept(Vec<Key>),
+    /// Only attributes with `i64` and `f64` values and one of the given keys become custom
+    /// measurements.
+    Only(Vec<Key>),
+    /// All attributes become custom properties.
+    #[default]
+    Disabled,
+}
+
+impl MeasurementPolicy {
+    fn includes(&self, key: &Key) -> bool {
+        match self {
+            MeasurementPolicy::Numeric => true,
+            MeasurementPolicy::NumericExcept(keys) => !keys.contains(key),
+            MeasurementPolicy::Only(keys) => keys.contains(key),
+            MeasurementPolicy::Disabled => false,
+        }
     }
 
+    /// Moves the numeric attributes selected by this policy from the properties into custom
+    /// measurements.
+    pub(crate) fn apply<'a>(
+        &self,
+        attributes: impl IntoIterator<Item = (&'a Key, &'a Value)>,
+        properties: &mut Option<Properties>,
+    ) -> Option<Measurements> {
+        let mut measurements = Measurements::new();
+        for (key, value) in attributes {
+            let value = match value {
+                Value::I64(value) => *value as f64,
+                Value::F64(value) => *value,
+                _ => continue,
+            };
+            if self.includes(key) {
+                if let Some(properties) = properties.as_mut() {
+                    properties.remove(&key.as_str().into());
+                }
+                measurements.insert(key.as_str().into(), value);
+            }
+        }
 
+        if properties.as_ref().is_some_and(|x| x.is_empty()) {
+            *properties = None;
+        }
+        Some(measurements).filter(|x| !x.is_empty())
+    }
+}
+
+/// Maps log levels like the `level` attribute of events recorded by `tracing-opentelemetry` to
+/// severity levels. Levels are compared case insensitive.
+pub(crate) fn level_to_severity_level(level: &str) -> Option<SeverityLevel> {
+    let is = |name: &str| level.eq_ignore_ascii_case(name);
+    if is("TRACE") || is("DEBUG") {
+        Some(SeverityLevel::Verbose)
+    } else if is("INFO") {
+        Some(SeverityLevel::Information)
+    } else if is("WARN") || is("WARNING") {
+        Some(SeverityLevel::Warning)
+    } else if is("ERROR") {
+        Some(SeverityLevel::Error)
+    } else if is("CRITICAL") || is("FATAL") {
+        Some(SeverityLevel::Critical)
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use opentelemetry::KeyValue;
     use test_case::test_case;
 
     #[test_case(TraceId::invalid(),            "00000000000000000000000000000000" ; "zero")]
@@ -80,4 +157,57 @@ mod tests {
     fn duration(duration: Duration, expected: &'static str) {
         assert_eq!(expected.to_string(), duration_to_string(duration));
     }
+
+    #[test_case("TRACE",   Some(SeverityLevel::Verbose)     ; "trace")]
+    #[test_case("DEBUG",   Some(SeverityLevel::Verbose)     ; "debug")]
+    #[test_case("INFO",    Some(SeverityLevel::Information) ; "info")]
+    #[test_case("warn",    Some(SeverityLevel::Warning)     ; "lowercase warn")]
+    #[test_case("ERROR",   Some(SeverityLevel::Error)       ; "error")]
+    #[test_case("fatal",   Some(SeverityLevel::Critical)    ; "fatal")]
+    #[test_case("verbose", None                             ; "unknown")]
+    fn severity_level(level: &str, expected: Option<SeverityLevel>) {
+        assert_eq!(expected, level_to_severity_level(level));
+    }
+
+    fn apply(policy: MeasurementPolicy) -> (Vec<String>, Vec<String>) {
+        let attributes = [
+            KeyValue::new("count", 3),
+            KeyValue::new("ratio", 0.5),
+            KeyValue::new("name", "value"),
+        ];
+        let mut properties = Some(
+            attributes
+                .iter()
+                .map(|kv| (kv.key.as_str().into(), (&kv.value).into()))
+                .collect(),
+        );
+        let measurements = policy.apply(
+            attributes.iter().map(|kv| (&kv.key, &kv.value)),
+            &mut properties,
+        );
+        (
+            properties
+                .map(|p| p.keys().map(|k| k.as_ref().to_string()).collect())
+                .unwrap_or_default(),
+            measurements
+                .map(|m| m.keys().map(|k| k.as_ref().to_string()).collect())
+                .unwrap_or_default(),
+        )
+    }
+
+    #[test_case(MeasurementPolicy::Numeric,                           &["name"],                   &["count", "ratio"] ; "numeric")]
+    #[test_case(MeasurementPolicy::NumericExcept(vec!["count".into()]), &["count", "name"],          &["ratio"]          ; "numeric except")]
+    #[test_case(MeasurementPolicy::Only(vec!["count".into()]),          &["name", "ratio"],          &["count"]          ; "only")]
+    #[test_case(MeasurementPolicy::Disabled,                          &["count", "name", "ratio"], &[]                 ; "disabled")]
+    #[test_case(MeasurementPolicy::default(),                         &["count", "name", "ratio"], &[]                 ; "default")]
+    fn measurement_policy(policy: MeasurementPolicy, properties: &[&str], measurements: &[&str]) {
+        assert_eq!(
+            (to_strings(properties), to_strings(measurements)),
+            apply(policy)
+        );
+    }
+
+    fn to_strings(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
 }
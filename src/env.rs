@@ -0,0 +1,365 @@
+use crate::{
+    new_pipeline_from_connection_string, ConnectionStringError, PipelineBuilder, PipelineSampler,
+};
+use opentelemetry::{
+    sdk::{
+        self,
+        trace::{Sampler, ShouldSample},
+    },
+    Key, KeyValue,
+};
+use opentelemetry_semantic_conventions as semcov;
+use percent_encoding::percent_decode_str;
+
+pub(crate) const CONNECTION_STRING: &str = "APPLICATIONINSIGHTS_CONNECTION_STRING";
+pub(crate) const INGESTION_ENDPOINT: &str = "APPLICATIONINSIGHTS_INGESTION_ENDPOINT";
+pub(crate) const SERVICE_NAME: &str = "OTEL_SERVICE_NAME";
+pub(crate) const RESOURCE_ATTRIBUTES: &str = "OTEL_RESOURCE_ATTRIBUTES";
+pub(crate) const TRACES_SAMPLER: &str = "OTEL_TRACES_SAMPLER";
+pub(crate) const TRACES_SAMPLER_ARG: &str = "OTEL_TRACES_SAMPLER_ARG";
+
+/// Creates a pipeline builder from environment variables, which are read with the given function.
+pub(crate) fn pipeline_from_env<F>(var: F) -> Result<PipelineBuilder<()>, FromEnvError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+
+    let connection_string =
+        var(CONNECTION_STRING).ok_or(FromEnvError::MissingVariable(CONNECTION_STRING))?;
+    let mut builder = new_pipeline_from_connection_string(&connection_string)?;
+
+    if let Some(endpoint) = var(INGESTION_ENDPOINT) {
+        builder = builder
+            .with_endpoint(endpoint.trim_end_matches('/'))
+            .map_err(|_| invalid_value(INGESTION_ENDPOINT, &endpoint))?;
+    }
+
+    let mut attributes = match var(RESOURCE_ATTRIBUTES) {
+        Some(value) => parse_resource_attributes(&value)
+            .ok_or_else(|| invalid_value(RESOURCE_ATTRIBUTES, &value))?,
+        None => Vec::new(),
+    };
+    if let Some(service_name) = var(SERVICE_NAME) {
+        // OTEL_SERVICE_NAME takes precedence over service.name in OTEL_RESOURCE_ATTRIBUTES.
+        attributes.push(semcov::resource::SERVICE_NAME.string(service_name));
+    }
+
+    let sampler = match var(TRACES_SAMPLER) {
+        Some(sampler) => Some(parse_sampler(&sampler, var(TRACES_SAMPLER_ARG))?),
+        None => None,
+    };
+
+    if !attributes.is_empty() {
+        builder = builder.with_trace_config(
+            sdk::trace::Config::default().with_resource(sdk::Resource::new(attributes)),
+        );
+    }
+    if let Some((sampler, sample_rate)) = sampler {
+        builder.sampler = Some(PipelineSampler {
+            sampler,
+            // Application Insights expects the sample rate as a percentage.
+            sample_rate: sample_rate.map(|sample_rate| sample_rate * 100.0),
+            from_env: true,
+        });
+    }
+
+    Ok(builder)
+}
+
+/// Parses `key1=value1,key2=value2` with percent encoded values. Returns `None` if any of the
+/// pairs is invalid.
+fn parse_resource_attributes(value: &str) -> Option<Vec<KeyValue>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let key = key.trim();
+            if key.is_empty() {
+                return None;
+            }
+            let value = percent_decode_str(value.trim()).decode_utf8().ok()?;
+            Some(Key::new(key.to_string()).string(value.into_owned()))
+        })
+        .collect()
+}
+
+/// Parses a sampler as specified by the OpenTelemetry specification. Returns the sampler and, for
+/// ratio based samplers, the sample rate.
+fn parse_sampler(
+    sampler: &str,
+    arg: Option<String>,
+) -> Result<(Box<dyn ShouldSample>, Option<f64>), FromEnvError> {
+    let ratio = || match &arg {
+        Some(arg) => arg
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|ratio| (0.0..=1.0).contains(ratio))
+            .ok_or_else(|| invalid_value(TRACES_SAMPLER_ARG, arg)),
+        None => Ok(1.0),
+    };
+
+    Ok(match sampler.trim() {
+        "always_on" => (Box::new(Sampler::AlwaysOn), None),
+        "always_off" => (Box::new(Sampler::AlwaysOff), None),
+        "traceidratio" => {
+            let ratio = ratio()?;
+            (Box::new(Sampler::TraceIdRatioBased(ratio)), Some(ratio))
+        }
+        "parentbased_always_on" => (
+            Box::new(Sampler::ParentBased(Box::new(Sampler::AlwaysOn))),
+            None,
+        ),
+        "parentbased_always_off" => (
+            Box::new(Sampler::ParentBased(Box::new(Sampler::AlwaysOff))),
+            None,
+        ),
+        "parentbased_traceidratio" => {
+            let ratio = ratio()?;
+            (
+                Box::new(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    ratio,
+                )))),
+                Some(ratio),
+            )
+        }
+        _ => return Err(invalid_value(TRACES_SAMPLER, sampler)),
+    })
+}
+
+fn invalid_value(name: &'static str, value: &str) -> FromEnvError {
+    FromEnvError::InvalidValue {
+        name,
+        value: value.to_string(),
+    }
+}
+
+/// Errors that occurred while configuring the pipeline from environment variables.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum FromEnvError {
+    /// A required environment variable is not set.
+    #[error("environment variable {0} is not set")]
+    MissingVariable(&'static str),
+
+    /// The connection string is invalid.
+    #[error("environment variable APPLICATIONINSIGHTS_CONNECTION_STRING is invalid: {0}")]
+    ConnectionString(#[from] ConnectionStringError),
+
+    /// An environment variable has an invalid value.
+    #[error("environment variable {name} has invalid value {value:?}")]
+    InvalidValue {
+        /// Name of the environment variable.
+        name: &'static str,
+        /// Value of the environment variable.
+        value: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<PipelineBuilder<()>, FromEnvError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        pipeline_from_env(|name| vars.get(name).cloned())
+    }
+
+    fn resource_value(builder: &PipelineBuilder<()>, key: &str) -> Option<String> {
+        builder
+            .config
+            .as_ref()?
+            .resource
+            .as_ref()?
+            .iter()
+            .find(|(k, _)| k.as_str() == key)
+            .map(|(_, value)| value.as_str().into_owned())
+    }
+
+    #[test]
+    fn connection_string_is_required() {
+        assert!(matches!(
+            from_vars(&[]),
+            Err(FromEnvError::MissingVariable(CONNECTION_STRING))
+        ));
+        assert!(matches!(
+            from_vars(&[(CONNECTION_STRING, "Foo=bar")]),
+            Err(FromEnvError::ConnectionString(
+                ConnectionStringError::MissingInstrumentationKey
+            ))
+        ));
+    }
+
+    #[test]
+    fn connection_string() {
+        let builder = from_vars(&[(
+            CONNECTION_STRING,
+            "InstrumentationKey=key;IngestionEndpoint=https://westus2-0.in.applicationinsights.azure.com/",
+        )])
+        .unwrap();
+        assert_eq!("key", builder.instrumentation_key);
+        assert_eq!(
+            "https://westus2-0.in.applicationinsights.azure.com/v2/track",
+            builder.endpoint.unwrap().to_string()
+        );
+        assert!(builder.config.is_none());
+    }
+
+    #[test]
+    fn ingestion_endpoint_overrides_connection_string() {
+        let builder = from_vars(&[
+            (CONNECTION_STRING, "InstrumentationKey=key"),
+            (INGESTION_ENDPOINT, "http://localhost:8080/"),
+        ])
+        .unwrap();
+        assert_eq!(
+            "http://localhost:8080/v2/track",
+            builder.endpoint.unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn resource() {
+        let builder = from_vars(&[
+            (CONNECTION_STRING, "InstrumentationKey=key"),
+            (
+                RESOURCE_ATTRIBUTES,
+                "service.name=from-attributes, service.namespace=my%20namespace",
+            ),
+            (SERVICE_NAME, "from-service-name"),
+        ])
+        .unwrap();
+        assert_eq!(
+            Some("from-service-name".into()),
+            resource_value(&builder, "service.name")
+        );
+        assert_eq!(
+            Some("my namespace".into()),
+            resource_value(&builder, "service.namespace")
+        );
+    }
+
+    #[test]
+    fn explicit_builder_calls_take_precedence() {
+        let mut builder = from_vars(&[
+            (CONNECTION_STRING, "InstrumentationKey=key"),
+            (RESOURCE_ATTRIBUTES, "service.version=1.0.0"),
+            (SERVICE_NAME, "from-env"),
+            (TRACES_SAMPLER, "traceidratio"),
+            (TRACES_SAMPLER_ARG, "0.5"),
+        ])
+        .unwrap()
+        .with_service_name("explicit")
+        .with_sample_rate(0.25);
+        assert_eq!(
+            Some("explicit".into()),
+            resource_value(&builder, "service.name")
+        );
+        assert_eq!(
+            Some("1.0.0".into()),
+            resource_value(&builder, "service.version")
+        );
+        builder.take_config();
+        assert_eq!(Some(25.0), builder.sample_rate);
+    }
+
+    #[test]
+    fn sampler() {
+        let mut builder = from_vars(&[
+            (CONNECTION_STRING, "InstrumentationKey=key"),
+            (TRACES_SAMPLER, "parentbased_traceidratio"),
+            (TRACES_SAMPLER_ARG, "0.5"),
+        ])
+        .unwrap();
+        let config = builder.take_config().unwrap();
+        assert!(format!("{:?}", config.sampler).contains("TraceIdRatioBased(0.5)"));
+        assert_eq!(Some(50.0), builder.sample_rate);
+
+        let mut builder = from_vars(&[
+            (CONNECTION_STRING, "InstrumentationKey=key"),
+            (TRACES_SAMPLER, "always_off"),
+        ])
+        .unwrap();
+        assert!(builder.take_config().is_some());
+        assert_eq!(None, builder.sample_rate);
+    }
+
+    #[test]
+    fn trace_config_replaces_sampler() {
+        let mut builder = from_vars(&[
+            (CONNECTION_STRING, "InstrumentationKey=key"),
+            (TRACES_SAMPLER, "traceidratio"),
+            (TRACES_SAMPLER_ARG, "0.5"),
+        ])
+        .unwrap()
+        .with_trace_config(sdk::trace::Config::default().with_sampler(Sampler::AlwaysOn));
+        let config = builder.take_config().unwrap();
+        assert_eq!("AlwaysOn", format!("{:?}", config.sampler));
+        assert_eq!(None, builder.sample_rate);
+    }
+
+    #[test]
+    fn trace_config_without_sampler_keeps_sampler() {
+        let mut builder = from_vars(&[
+            (CONNECTION_STRING, "InstrumentationKey=key"),
+            (TRACES_SAMPLER, "traceidratio"),
+            (TRACES_SAMPLER_ARG, "0.5"),
+        ])
+        .unwrap()
+        .with_trace_config(
+            sdk::trace::Config::default().with_resource(sdk::Resource::new(vec![KeyValue::new(
+                "service.name",
+                "my-application",
+            )])),
+        );
+        assert_eq!(
+            Some("my-application".into()),
+            resource_value(&builder, "service.name")
+        );
+        let config = builder.take_config().unwrap();
+        assert_eq!("TraceIdRatioBased(0.5)", format!("{:?}", config.sampler));
+        assert_eq!(Some(50.0), builder.sample_rate);
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(matches!(
+            from_vars(&[
+                (CONNECTION_STRING, "InstrumentationKey=key"),
+                (TRACES_SAMPLER, "sometimes"),
+            ]),
+            Err(FromEnvError::InvalidValue {
+                name: TRACES_SAMPLER,
+                ..
+            })
+        ));
+        assert!(matches!(
+            from_vars(&[
+                (CONNECTION_STRING, "InstrumentationKey=key"),
+                (TRACES_SAMPLER, "traceidratio"),
+                (TRACES_SAMPLER_ARG, "1.5"),
+            ]),
+            Err(FromEnvError::InvalidValue {
+                name: TRACES_SAMPLER_ARG,
+                ..
+            })
+        ));
+        assert!(matches!(
+            from_vars(&[
+                (CONNECTION_STRING, "InstrumentationKey=key"),
+                (RESOURCE_ATTRIBUTES, "no-value"),
+            ]),
+            Err(FromEnvError::InvalidValue {
+                name: RESOURCE_ATTRIBUTES,
+                ..
+            })
+        ));
+    }
+}
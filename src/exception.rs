@@ -0,0 +1,446 @@
+use crate::models::{ExceptionDetails, StackFrame};
+use opentelemetry::{trace::Span, Key, KeyValue, Value};
+use opentelemetry_semantic_conventions as semcov;
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    iter,
+};
+
+/// Maximum accumulated length of the frames in a parsed stack. Middle frames are dropped to stay
+/// within this limit.
+const MAX_PARSED_STACK_LENGTH: usize = 32768;
+
+/// Maximum length of a stack sent as text. Longer stacks are truncated.
+const MAX_STACK_LENGTH: usize = 32768;
+
+/// Prefix of the attributes describing the sources of an exception, e.g.
+/// `exception.source.0.message` for the message of the direct source.
+const SOURCE_PREFIX: &str = "exception.source.";
+
+/// Record an error on a span as an `exception` event, which includes the whole chain of sources
+/// (see [`Error::source`]). The exporter sends the error and each of its sources as a separate
+/// exception in the same Exception telemetry item.
+///
+/// The type name of the error is recorded as `exception.type` unless the error is a trait object.
+/// Sources are recorded as `exception.source.<index>.message`, starting with index 0 for the
+/// direct source of the error.
+///
+/// ```
+/// use opentelemetry::{sdk, trace::{Tracer as _, TracerProvider as _}};
+/// use std::fs::File;
+///
+/// let tracer = sdk::trace::TracerProvider::default().get_tracer("example", None);
+/// let mut span = tracer.start("open config");
+/// if let Err(err) = File::open("config.toml") {
+///     opentelemetry_application_insights::record_error(&mut span, &err);
+/// }
+/// ```
+pub fn record_error<S, E>(span: &mut S, error: &E)
+where
+    S: Span + ?Sized,
+    E: Error + ?Sized,
+{
+    span.add_event("exception".into(), error_attributes(error));
+}
+
+pub(crate) fn error_attributes<E: Error + ?Sized>(error: &E) -> Vec<KeyValue> {
+    let mut attributes = Vec::new();
+    let type_name = std::any::type_name::<E>();
+    if !type_name.starts_with("dyn ") {
+        attributes.push(KeyValue::new(semcov::trace::EXCEPTION_TYPE, type_name));
+    }
+    attributes.push(KeyValue::new(
+        semcov::trace::EXCEPTION_MESSAGE,
+        error.to_string(),
+    ));
+    let sources = iter::successors(error.source(), |&source| source.source());
+    for (index, source) in sources.enumerate() {
+        attributes.push(KeyValue::new(
+            format!("{}{}.message", SOURCE_PREFIX, index),
+            source.to_string(),
+        ));
+    }
+
+    attributes
+}
+
+#[derive(Default)]
+struct ExceptionAttributes<'a> {
+    type_name: Option<&'a Value>,
+    message: Option<&'a Value>,
+    stack: Option<&'a Value>,
+}
+
+impl From<ExceptionAttributes<'_>> for ExceptionDetails {
+    fn from(attributes: ExceptionAttributes<'_>) -> ExceptionDetails {
+        let stack = attributes.stack.map(Value::as_str);
+        let mut parsed_stack = stack.as_deref().and_then(parse_stack);
+        let has_full_stack = match (&mut parsed_stack, &stack) {
+            (Some(frames), _) => Some(limit_stack(frames)),
+            (None, Some(stack)) => Some(stack.len() <= MAX_STACK_LENGTH),
+            (None, None) => None,
+        };
+        ExceptionDetails {
+            id: None,
+            outer_id: None,
+            type_name: attributes
+                .type_name
+                .map(Into::into)
+                .unwrap_or_else(|| "<no type>".into()),
+            message: attributes
+                .message
+                .map(Into::into)
+                .unwrap_or_else(|| "<no message>".into()),
+            has_full_stack,
+            stack: stack.filter(|_| parsed_stack.is_none()).map(Into::into),
+            parsed_stack,
+        }
+    }
+}
+
+/// Removes the attributes describing an exception and its sources and converts them into the
+/// exception chain. The exception comes first, followed by its sources ordered by index.
+pub(crate) fn take_exception_details(attrs: &mut HashMap<&Key, &Value>) -> Vec<ExceptionDetails> {
+    let exception = ExceptionAttributes {
+        type_name: attrs.remove(&semcov::trace::EXCEPTION_TYPE),
+        message: attrs.remove(&semcov::trace::EXCEPTION_MESSAGE),
+        stack: attrs.remove(&semcov::trace::EXCEPTION_STACKTRACE),
+    };
+
+    let mut sources: BTreeMap<usize, ExceptionAttributes<'_>> = BTreeMap::new();
+    attrs.retain(|key, value| {
+        let source = key
+            .as_str()
+            .strip_prefix(SOURCE_PREFIX)
+            .and_then(|rest| rest.split_once('.'))
+            .and_then(|(index, field)| Some((index.parse::<usize>().ok()?, field)));
+        match source {
+            Some((index, "type")) => sources.entry(index).or_default().type_name = Some(*value),
+            Some((index, "message")) => sources.entry(index).or_default().message = Some(*value),
+            Some((index, "stacktrace")) => sources.entry(index).or_default().stack = Some(*value),
+            _ => return true,
+        }
+        false
+    });
+
+    let mut exceptions = vec![ExceptionDetails::from(exception)];
+    if !sources.is_empty() {
+        exceptions[0].id = Some(1);
+        for (outer_id, source) in (1..).zip(sources.into_values()) {
+            let mut details = ExceptionDetails::from(source);
+            details.id = Some(outer_id + 1);
+            details.outer_id = Some(outer_id);
+            exceptions.push(details);
+        }
+    }
+
+    exceptions
+}
+
+/// Parses a stack trace in the format of `std::backtrace::Backtrace` and
+/// `backtrace::Backtrace`. Returns `None` if the stack trace has a different format.
+///
+/// ```text
+///    0: app::handler
+///              at ./src/main.rs:12:5
+///       app::inlined_helper
+///              at ./src/helper.rs:3:9
+///    1: main
+/// ```
+fn parse_stack(stack: &str) -> Option<Vec<StackFrame>> {
+    let mut frames: Vec<StackFrame> = Vec::new();
+    for line in stack.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        if let Some(location) = trimmed.strip_prefix("at ") {
+            let frame = frames
+                .last_mut()
+                .filter(|frame| frame.file_name.is_none())?;
+            let (file_name, line) = parse_location(location)?;
+            frame.file_name = Some(file_name.into());
+            frame.line = Some(line);
+            continue;
+        }
+
+        let method = match trimmed.split_once(": ") {
+            Some((index, method))
+                if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                method
+            }
+            // Inlined functions are listed without index below the frame they are part of.
+            _ if line.starts_with(' ') && !frames.is_empty() => trimmed,
+            _ => return None,
+        };
+        frames.push(StackFrame {
+            level: frames.len() as i32,
+            method: method.into(),
+            assembly: crate_name(method).map(Into::into),
+            file_name: None,
+            line: None,
+        });
+    }
+
+    Some(frames).filter(|frames| !frames.is_empty())
+}
+
+/// Parses a location like `src/main.rs:12:5` or `src/main.rs:12` into file name and line.
+fn parse_location(location: &str) -> Option<(&str, i32)> {
+    let (rest, last) = location.rsplit_once(':')?;
+    let last = last.parse().ok()?;
+    let file_and_line = rest
+        .rsplit_once(':')
+        .and_then(|(file_name, line)| Some((file_name, line.parse().ok()?)));
+    Some(file_and_line.unwrap_or((rest, last)))
+}
+
+/// Returns the first path segment of a symbol like `app::handler`, which is the crate name.
+fn crate_name(method: &str) -> Option<&str> {
+    let (name, _) = method.split_once("::")?;
+    Some(name)
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+fn frame_len(frame: &StackFrame) -> usize {
+    // Level and line are counted as 4 bytes each, like the Application Insights SDKs do.
+    8 + frame.method.as_ref().len()
+        + frame.assembly.as_ref().map_or(0, |x| x.as_ref().len())
+        + frame.file_name.as_ref().map_or(0, |x| x.as_ref().len())
+}
+
+/// Drops frames from the middle of the stack until it fits into `MAX_PARSED_STACK_LENGTH`. Frames
+/// keep their original level. Returns `true` if all frames were kept.
+fn limit_stack(frames: &mut Vec<StackFrame>) -> bool {
+    let mut total = 0;
+    let mut fits = |frame: &StackFrame| {
+        total += frame_len(frame);
+        total <= MAX_PARSED_STACK_LENGTH
+    };
+
+    // Take frames alternately from the top and the bottom of the stack.
+    let (mut top, mut bottom) = (0, frames.len());
+    while top < bottom {
+        if !fits(&frames[top]) {
+            break;
+        }
+        top += 1;
+        if top == bottom || !fits(&frames[bottom - 1]) {
+            break;
+        }
+        bottom -= 1;
+    }
+
+    if top == bottom {
+        return true;
+    }
+    frames.drain(top..bottom);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("request failed")]
+    struct RequestError(#[source] ConnectError);
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("connect failed")]
+    struct ConnectError(#[source] io::Error);
+
+    fn exception_details(attributes: &[KeyValue]) -> serde_json::Value {
+        let mut attrs = attributes.iter().map(|kv| (&kv.key, &kv.value)).collect();
+        let details = take_exception_details(&mut attrs);
+        assert!(attrs.is_empty());
+        serde_json::to_value(details).unwrap()
+    }
+
+    #[test]
+    fn error_chain() {
+        let error = RequestError(ConnectError(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "refused",
+        )));
+
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "id": 1,
+                    "typeName": std::any::type_name::<RequestError>(),
+                    "message": "request failed",
+                },
+                {
+                    "id": 2,
+                    "outerId": 1,
+                    "typeName": "<no type>",
+                    "message": "connect failed",
+                },
+                {
+                    "id": 3,
+                    "outerId": 2,
+                    "typeName": "<no type>",
+                    "message": "refused",
+                },
+            ]),
+            exception_details(&error_attributes(&error))
+        );
+    }
+
+    #[test]
+    fn trait_object_without_sources() {
+        let error: Box<dyn Error> = "failed".into();
+
+        assert_eq!(
+            serde_json::json!([{ "typeName": "<no type>", "message": "failed" }]),
+            exception_details(&error_attributes(error.as_ref()))
+        );
+    }
+
+    #[test]
+    fn sources_with_type_and_stack() {
+        let attributes = [
+            KeyValue::new("exception.type", "Outer"),
+            KeyValue::new("exception.message", "outer"),
+            KeyValue::new("exception.stacktrace", "at outer"),
+            KeyValue::new("exception.source.1.message", "second"),
+            KeyValue::new("exception.source.0.type", "Inner"),
+            KeyValue::new("exception.source.0.message", "first"),
+            KeyValue::new("exception.source.0.stacktrace", "at inner"),
+        ];
+
+        assert_eq!(
+            serde_json::json!([
+                {
+                    "id": 1,
+                    "typeName": "Outer",
+                    "message": "outer",
+                    "hasFullStack": true,
+                    "stack": "at outer",
+                },
+                {
+                    "id": 2,
+                    "outerId": 1,
+                    "typeName": "Inner",
+                    "message": "first",
+                    "hasFullStack": true,
+                    "stack": "at inner",
+                },
+                {
+                    "id": 3,
+                    "outerId": 2,
+                    "typeName": "<no type>",
+                    "message": "second",
+                },
+            ]),
+            exception_details(&attributes)
+        );
+    }
+
+    #[test]
+    fn parsed_stack() {
+        let attributes = [
+            KeyValue::new("exception.message", "failed"),
+            KeyValue::new(
+                "exception.stacktrace",
+                concat!(
+                    "   0: app::handler\n",
+                    "             at ./src/main.rs:12:5\n",
+                    "      app::helper::{{closure}}\n",
+                    "             at C:\\app\\src\\helper.rs:3\n",
+                    "   1: <&dyn core::ops::Fn<()> as core::ops::FnOnce<()>>::call_once\n",
+                    "   2: main\n",
+                ),
+            ),
+        ];
+
+        assert_eq!(
+            serde_json::json!([{
+                "typeName": "<no type>",
+                "message": "failed",
+                "hasFullStack": true,
+                "parsedStack": [
+                    {
+                        "level": 0,
+                        "method": "app::handler",
+                        "assembly": "app",
+                        "fileName": "./src/main.rs",
+                        "line": 12,
+                    },
+                    {
+                        "level": 1,
+                        "method": "app::helper::{{closure}}",
+                        "assembly": "app",
+                        "fileName": "C:\\app\\src\\helper.rs",
+                        "line": 3,
+                    },
+                    {
+                        "level": 2,
+                        "method": "<&dyn core::ops::Fn<()> as core::ops::FnOnce<()>>::call_once",
+                    },
+                    {
+                        "level": 3,
+                        "method": "main",
+                    },
+                ],
+            }]),
+            exception_details(&attributes)
+        );
+    }
+
+    #[test]
+    fn parsed_stack_drops_middle_frames() {
+        let stack: String = (0..2000)
+            .map(|i| format!("{:4}: app::function_{}\n at src/lib.rs:{}:1\n", i, i, i))
+            .collect();
+        let mut frames = parse_stack(&stack).unwrap();
+
+        assert!(!limit_stack(&mut frames));
+        assert!(frames.iter().map(frame_len).sum::<usize>() <= MAX_PARSED_STACK_LENGTH);
+        assert_eq!(0, frames.first().unwrap().level);
+        assert_eq!(1999, frames.last().unwrap().level);
+        let levels: Vec<_> = frames.iter().map(|frame| frame.level).collect();
+        let gap = levels.windows(2).position(|w| w[1] != w[0] + 1).unwrap();
+        assert!(levels[gap + 1..].windows(2).all(|w| w[1] == w[0] + 1));
+        assert!(levels[..gap + 1].len().abs_diff(levels[gap + 1..].len()) <= 1);
+    }
+
+    #[test]
+    fn unparsable_stack_is_sent_as_text() {
+        let attributes = [
+            KeyValue::new("exception.message", "failed"),
+            KeyValue::new(
+                "exception.stacktrace",
+                "Error: failed\n    at handler (app.js:1:1)",
+            ),
+        ];
+
+        assert_eq!(
+            serde_json::json!([{
+                "typeName": "<no type>",
+                "message": "failed",
+                "hasFullStack": true,
+                "stack": "Error: failed\n    at handler (app.js:1:1)",
+            }]),
+            exception_details(&attributes)
+        );
+    }
+
+    #[test]
+    fn truncated_stack_is_not_full() {
+        let stack = "Error: failed\n".repeat(3000);
+        let attributes = [KeyValue::new("exception.stacktrace", stack)];
+
+        let details = exception_details(&attributes);
+        assert_eq!(false, details[0]["hasFullStack"]);
+        assert_eq!(
+            MAX_STACK_LENGTH,
+            details[0]["stack"].as_str().unwrap().len()
+        );
+    }
+}
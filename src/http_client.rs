@@ -10,18 +10,27 @@ use http::{Request, Response};
 use std::convert::TryInto;
 use std::fmt::Debug;
 
-type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 /// HTTP client used by the exporter to send telemetry to Application Insights
 ///
 /// This trait can be implemented for different async runtimes, which makes the exporter agnostic
 /// to any runtime the user may choose.
+///
+/// Implementations must send the request's method and headers as given. Besides uploads, the
+/// built-in token credentials use it to request access tokens.
 #[async_trait]
 pub trait HttpClient: Debug + Send + Sync {
     /// Send telemetry to Application Insights
     ///
-    /// This may fail if it can't connect to the server or if the request cannot be completed due
-    /// to redirects. In those cases the exporter will retry the request.
+    /// This may fail if it can't connect to the server. In this case the exporter will retry the
+    /// request.
+    ///
+    /// Implementations don't need to follow redirects. The exporter follows `307` and `308`
+    /// redirects itself and reuses the redirected endpoint for subsequent uploads.
+    ///
+    /// The response should include the response headers, so the exporter can honor the
+    /// `Retry-After` header sent by Application Insights.
     async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, BoxError>;
 }
 
@@ -33,9 +42,11 @@ pub trait HttpClient: Debug + Send + Sync {
 impl HttpClient for reqwest::Client {
     async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, BoxError> {
         let res = self.execute(request.try_into()?).await?;
-        Ok(Response::builder()
-            .status(res.status())
-            .body(res.bytes().await?)?)
+        let mut response = Response::builder().status(res.status());
+        if let Some(headers) = response.headers_mut() {
+            *headers = res.headers().clone();
+        }
+        Ok(response.body(res.bytes().await?)?)
     }
 }
 
@@ -50,9 +61,11 @@ impl HttpClient for reqwest::Client {
 impl HttpClient for reqwest::blocking::Client {
     async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, BoxError> {
         let res = self.execute(request.try_into()?)?;
-        Ok(Response::builder()
-            .status(res.status())
-            .body(res.bytes()?)?)
+        let mut response = Response::builder().status(res.status());
+        if let Some(headers) = response.headers_mut() {
+            *headers = res.headers().clone();
+        }
+        Ok(response.body(res.bytes()?)?)
     }
 }
 
@@ -64,12 +77,67 @@ impl HttpClient for reqwest::blocking::Client {
 impl HttpClient for surf::Client {
     async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, BoxError> {
         let (parts, body) = request.into_parts();
-        let req = surf::post(parts.uri.to_string())
-            .content_type("application/json")
-            .body(body);
+        let method: surf::http::Method = parts.method.as_str().parse()?;
+        let mut req = surf::RequestBuilder::new(method, surf::Url::parse(&parts.uri.to_string())?);
+        for (name, value) in parts.headers.iter() {
+            req = req.header(name.as_str(), value.to_str()?);
+        }
+        // Set the body after the headers, so it doesn't override the content type.
+        let req = req.body(body);
         let mut res = self.send(req).await?;
-        Ok(Response::builder()
-            .status(res.status() as u16)
-            .body(res.body_bytes().await?.into())?)
+        let mut response = Response::builder().status(res.status() as u16);
+        for (name, values) in res.iter() {
+            for value in values {
+                response = response.header(name.as_str(), value.as_str());
+            }
+        }
+        Ok(response.body(res.body_bytes().await?.into())?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    /// Accepts every upload and records the request bodies and authorization headers.
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct RecordingClient {
+        bodies: Arc<Mutex<Vec<Vec<u8>>>>,
+        authorizations: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl RecordingClient {
+        /// All envelopes uploaded so far.
+        pub(crate) fn envelopes(&self) -> Vec<Value> {
+            self.bodies
+                .lock()
+                .unwrap()
+                .iter()
+                .flat_map(|body| serde_json::from_slice::<Vec<Value>>(body).unwrap())
+                .collect()
+        }
+
+        /// Authorization headers of all uploads so far.
+        #[cfg(feature = "logs")]
+        pub(crate) fn authorizations(&self) -> Vec<Option<String>> {
+            self.authorizations.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for RecordingClient {
+        async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, BoxError> {
+            self.authorizations.lock().unwrap().push(
+                request
+                    .headers()
+                    .get(http::header::AUTHORIZATION)
+                    .and_then(|authorization| authorization.to_str().ok())
+                    .map(Into::into),
+            );
+            self.bodies.lock().unwrap().push(request.into_body());
+            Ok(Response::builder().status(200).body(Bytes::new())?)
+        }
     }
 }
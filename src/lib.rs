@@ -23,6 +23,36 @@
 //! }
 //! ```
 //!
+//! ## Connection strings
+//!
+//! Instead of an instrumentation key you can configure the exporter with a [connection string].
+//! This also configures the ingestion endpoint, e.g. for sovereign clouds or regional endpoints
+//! (this example requires the **reqwest-client-blocking** feature):
+//!
+//! ```no_run
+//! use opentelemetry::trace::Tracer as _;
+//!
+//! fn main() {
+//!     let connection_string = std::env::var("APPLICATIONINSIGHTS_CONNECTION_STRING").unwrap();
+//!     let tracer =
+//!         opentelemetry_application_insights::new_pipeline_from_connection_string(&connection_string)
+//!             .expect("valid connection string")
+//!             .with_client(reqwest::blocking::Client::new())
+//!             .install_simple();
+//!
+//!     tracer.in_span("main", |_cx| {});
+//! }
+//! ```
+//!
+//! [connection string]: https://docs.microsoft.com/en-us/azure/azure-monitor/app/sdk-connection-string
+//!
+//! ## Azure Active Directory authentication
+//!
+//! If local authentication is disabled for your Application Insights resource, uploads must be
+//! authenticated with an Azure Active Directory access token. Configure a `TokenCredential` with
+//! `with_token_credential`. This crate comes with credentials for managed identities
+//! (`ManagedIdentityCredential`) and service principals (`ClientSecretCredential`).
+//!
 //! ## Simple or Batch
 //!
 //! The functions `build_simple` and `install_simple` build/install a trace pipeline using the
@@ -71,6 +101,43 @@
 //!
 //! Alternatively you can bring any other HTTP client by implementing the `HttpClient` trait.
 //!
+//! Enable the **compression** feature to gzip uploads with `with_compression(true)`. This works
+//! with any HTTP client.
+//!
+//! ## Metrics
+//!
+//! Enable the **metrics** feature to export OpenTelemetry metrics as Application Insights metric
+//! telemetry. Configure a metrics pipeline with `new_metrics_pipeline`, which uses the same HTTP
+//! clients, endpoints and resource mapping as the trace pipeline.
+//!
+//! | OpenTelemetry instrument         | Application Insights metric                       |
+//! | -------------------------------- | ------------------------------------------------- |
+//! | Counter, UpDownCounter           | Sum of the values recorded since the last export  |
+//! | SumObserver, UpDownSumObserver   | Observed value                                    |
+//! | ValueObserver                    | Last observed value                               |
+//! | ValueRecorder                    | Aggregation with sum, count, min, max and std dev |
+//!
+//! The instrumentation name becomes the metric namespace and labels become custom dimensions.
+//!
+//! ## Logs
+//!
+//! Enable the **logs** feature to send records of the [`log`] crate to Application Insights as
+//! trace telemetry. Install a logger with `new_log_pipeline(...).install_batch(runtime)`. Records
+//! are correlated with the current span and uploaded in batches from a background task. The
+//! level, target, module path, file and line of a record become custom properties.
+//!
+//! [`log`]: https://crates.io/crates/log
+//!
+//! ## Telemetry client
+//!
+//! Telemetry which doesn't fit into spans, e.g. during startup or in command line tools, can be
+//! tracked with a `TelemetryClient`. Build one with
+//! `new_pipeline(...).build_telemetry_client(runtime)`. It provides `track_event`, `track_trace`,
+//! `track_exception`, `track_metric`, `track_dependency`, `track_request` and
+//! `track_availability`. Telemetry is correlated with the current span and uploaded in batches
+//! from a background task. Call `flush` or, in async code, `flush_async` before your program
+//! exits.
+//!
 //! # Attribute mapping
 //!
 //! OpenTelemetry and Application Insights are using different terminology. This crate tries it's
@@ -88,6 +155,13 @@
 //! | `CLIENT`, `PRODUCER`, `INTERNAL` | Dependency                          |
 //! | `SERVER`, `CONSUMER`             | Request                             |
 //!
+//! Spans with the attribute `ai.availability.run_location` (see `AVAILABILITY_RUN_LOCATION` and
+//! `track_availability`) are converted into Availability telemetry instead. The attribute
+//! determines the Run location and the status description the Message.
+//!
+//! Spans with the attribute `ai.page_view` set to `true` (see `PAGE_VIEW`) are converted into
+//! PageView telemetry with the Span's name, duration and `http.url` attribute.
+//!
 //! The Span's status determines the Success field of a Dependency or Request. Success is `false` if
 //! the status `Error`; otherwise `true`.
 //!
@@ -126,10 +200,20 @@
 //! | `net.peer.ip`                                     | Request Source                                           |
 //! | `http.status_code`                                | Request Response code                                    |
 //!
-//! All other attributes are directly converted to custom properties.
+//! All other attributes are directly converted to custom properties. Use `with_measurement_policy`
+//! to send attributes with numeric values as custom measurements instead.
 //!
 //! For Requests the attributes `http.method` and `http.route` override the Name.
 //!
+//! The sample rate of the Span's telemetry is taken from the attribute `ai.sample_rate` (see
+//! `SAMPLE_RATE`), which the samplers of this crate record on sampled spans, or from the sampling
+//! threshold (`th`) or p-value (`p`) in the `ot` entry of the W3C trace state, which parent based
+//! samplers pass on to child spans. Otherwise the configured sample rate is used.
+//!
+//! The description of the span status is sent as the custom property `otel.status_description`.
+//! Use `with_exceptions_for_error_spans` to additionally send Exception telemetry for spans with
+//! status `Error`, which don't have an `exception` event.
+//!
 //! ## Events
 //!
 //! Events are converted into Exception telemetry if the event name equals `"exception"` (see
@@ -141,28 +225,82 @@
 //! | `exception.message`         | Exception message          |
 //! | `exception.stacktrace`      | Exception call stack       |
 //!
-//! All other events are converted into Trace telemetry.
+//! Stack traces in the format of `std::backtrace::Backtrace` and the [`backtrace`] crate are sent
+//! as parsed stack frames. Frames in the middle of very long stack traces are dropped. Stack
+//! traces in other formats are sent as text.
+//!
+//! Sources of the exception can be described with `exception.source.<index>.type`,
+//! `exception.source.<index>.message` and `exception.source.<index>.stacktrace`, where index 0 is
+//! the direct source. They are sent as nested exceptions. Use `record_error` to record a Rust
+//! error including its chain of sources and `install_panic_hook` to report panics as exceptions.
+//!
+//! Events with a name registered with `with_custom_event_names` are converted into custom Event
+//! telemetry.
+//!
+//! All other events are converted into Trace telemetry. The severity level is derived from the
+//! `level` attribute as recorded by `tracing-opentelemetry` (`TRACE` and `DEBUG` map to Verbose,
+//! `INFO` to Information, `WARN` to Warning and `ERROR` to Error). Use
+//! `with_severity_level_mapping` for other conventions.
 //!
 //! All other attributes are directly converted to custom properties.
 //!
+//! [`backtrace`]: https://crates.io/crates/backtrace
 //! [exceptions]: https://github.com/open-telemetry/opentelemetry-specification/blob/master/specification/trace/semantic_conventions/exceptions.md
 #![doc(html_root_url = "https://docs.rs/opentelemetry-application-insights/0.14.0")]
 #![deny(missing_docs, unreachable_pub, missing_debug_implementations)]
 #![cfg_attr(test, deny(warnings))]
 
+mod auth;
+mod availability;
+mod batch;
+mod connection_string;
 mod convert;
+mod env;
+mod exception;
 mod http_client;
+#[cfg(feature = "logs")]
+mod logs;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod models;
+mod panic_hook;
+mod sampler;
+mod storage;
 mod tags;
+mod telemetry_client;
 mod uploader;
 
 use async_trait::async_trait;
-use convert::{attrs_to_properties, duration_to_string, span_id_to_string, time_to_string};
+use auth::CachedCredential;
+pub use auth::{
+    AccessToken, ClientSecretCredential, ManagedIdentityCredential, StaticTokenCredential,
+    TokenCredential,
+};
+pub use availability::{track_availability, AvailabilityResult, AVAILABILITY_RUN_LOCATION};
+pub use connection_string::{ConnectionString, ConnectionStringError};
+pub use convert::MeasurementPolicy;
+use convert::{
+    attrs_to_properties, duration_to_string, level_to_severity_level, span_id_to_string,
+    time_to_string,
+};
+pub use env::FromEnvError;
+pub use exception::record_error;
+use exception::take_exception_details;
 pub use http_client::HttpClient;
+#[cfg(feature = "logs")]
+pub use logs::{
+    new_log_pipeline, new_log_pipeline_from_connection_string, LogPipelineBuilder, Logger,
+};
+#[cfg(feature = "metrics")]
+pub use metrics::{
+    new_metrics_pipeline, new_metrics_pipeline_from_connection_string, MetricsExporter,
+    MetricsPipelineBuilder,
+};
 pub use models::context_tag_keys::attrs;
+pub use models::SeverityLevel;
 use models::{
-    Data, Envelope, ExceptionData, ExceptionDetails, LimitedLenString1024, MessageData, Properties,
-    RemoteDependencyData, RequestData,
+    AvailabilityData, Data, Envelope, EventData, ExceptionData, ExceptionDetails,
+    LimitedLenString1024, MessageData, PageViewData, Properties, RemoteDependencyData, RequestData,
 };
 use opentelemetry::{
     global,
@@ -174,12 +312,39 @@ use opentelemetry::{
             ExportError,
         },
     },
-    trace::{Event, SpanKind, StatusCode, TracerProvider},
+    trace::{Event, SpanKind, StatusCode, TraceError, TracerProvider},
     Key, Value,
 };
 use opentelemetry_semantic_conventions as semcov;
-use std::{borrow::Cow, collections::HashMap, convert::TryInto, error::Error as StdError};
+pub use panic_hook::install_panic_hook;
+use sampler::span_sample_rate;
+pub use sampler::{AdaptiveSampler, ApplicationInsightsSampler, SAMPLE_RATE};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use storage::{Storage, StorageLimits};
 use tags::{get_tags_for_event, get_tags_for_span};
+pub use telemetry_client::{DependencyTelemetry, RequestTelemetry, TelemetryClient};
+use uploader::{BatchLimits, RetryPolicy, Uploader};
+pub use uploader::{Transmission, TransmissionItem};
+
+const DEFAULT_ENDPOINT: &str = "https://dc.services.visualstudio.com/v2/track";
+
+/// Attribute, which marks a span as a page view. Spans with this attribute set to `true` are sent
+/// as PageView telemetry.
+pub const PAGE_VIEW: Key = Key::from_static_str("ai.page_view");
+
+/// Property containing the description of the span status.
+const STATUS_DESCRIPTION: &str = "otel.status_description";
 
 /// Create a new Application Insights exporter pipeline builder
 pub fn new_pipeline(instrumentation_key: String) -> PipelineBuilder<()> {
@@ -189,9 +354,48 @@ pub fn new_pipeline(instrumentation_key: String) -> PipelineBuilder<()> {
         endpoint: None,
         instrumentation_key,
         sample_rate: None,
+        sampler: None,
+        adaptive_sampler: None,
+        retry_policy: RetryPolicy::default(),
+        batch_limits: BatchLimits::default(),
+        storage: None,
+        storage_limits: StorageLimits::default(),
+        credential: None,
+        #[cfg(feature = "compression")]
+        compression: None,
+        severity_level_mapping: None,
+        custom_event_names: HashSet::new(),
+        measurement_policy: MeasurementPolicy::default(),
+        exceptions_for_error_spans: false,
     }
 }
 
+/// Create a new Application Insights exporter pipeline builder from a [connection string]. The
+/// connection string determines the instrumentation key and the ingestion endpoint.
+///
+/// [connection string]: https://docs.microsoft.com/en-us/azure/azure-monitor/app/sdk-connection-string
+pub fn new_pipeline_from_connection_string(
+    connection_string: &str,
+) -> Result<PipelineBuilder<()>, ConnectionStringError> {
+    let connection_string: ConnectionString = connection_string.parse()?;
+    let mut builder = new_pipeline(connection_string.instrumentation_key().to_string());
+    builder.endpoint = Some(connection_string.track_endpoint());
+    Ok(builder)
+}
+
+/// Sampler, which is installed when the pipeline is built, together with the sample rate it
+/// samples with. This keeps the sample rate passed through to Application Insights in line with
+/// the sampler.
+#[derive(Debug)]
+struct PipelineSampler {
+    sampler: Box<dyn sdk::trace::ShouldSample>,
+    /// Sample rate as percentage. `None` if it's unknown or varies per span.
+    sample_rate: Option<f64>,
+    /// Samplers configured with environment variables are replaced by the sampler of a trace
+    /// config set afterwards.
+    from_env: bool,
+}
+
 /// Application Insights exporter pipeline builder
 #[derive(Debug)]
 pub struct PipelineBuilder<C> {
@@ -200,6 +404,55 @@ pub struct PipelineBuilder<C> {
     endpoint: Option<http::Uri>,
     instrumentation_key: String,
     sample_rate: Option<f64>,
+    sampler: Option<PipelineSampler>,
+    adaptive_sampler: Option<AdaptiveSampler>,
+    retry_policy: RetryPolicy,
+    batch_limits: BatchLimits,
+    storage: Option<Storage>,
+    storage_limits: StorageLimits,
+    credential: Option<Box<dyn TokenCredential>>,
+    #[cfg(feature = "compression")]
+    compression: Option<flate2::Compression>,
+    severity_level_mapping: Option<SeverityLevelMapping>,
+    custom_event_names: HashSet<Cow<'static, str>>,
+    measurement_policy: MeasurementPolicy,
+    exceptions_for_error_spans: bool,
+}
+
+impl PipelineBuilder<()> {
+    /// Create a new Application Insights exporter pipeline builder configured from environment
+    /// variables:
+    ///
+    /// | Environment variable                     | Configures                                        |
+    /// | ---------------------------------------- | ------------------------------------------------- |
+    /// | `APPLICATIONINSIGHTS_CONNECTION_STRING`  | Instrumentation key and endpoint (required)       |
+    /// | `APPLICATIONINSIGHTS_INGESTION_ENDPOINT` | Endpoint, overrides the connection string         |
+    /// | `OTEL_SERVICE_NAME`                      | `service.name` resource attribute                 |
+    /// | `OTEL_RESOURCE_ATTRIBUTES`               | Resource attributes (`key1=value1,key2=value2`)   |
+    /// | `OTEL_TRACES_SAMPLER`                    | Sampler (e.g. `parentbased_traceidratio`)         |
+    /// | `OTEL_TRACES_SAMPLER_ARG`                | Ratio for ratio based samplers, also sample rate  |
+    ///
+    /// The resource is merged into the SDK config the same way as with `with_trace_config`.
+    /// Subsequent calls of builder functions take precedence over environment variables. The
+    /// sampler is only replaced by a trace config with a sampler other than the SDK default.
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// use opentelemetry_application_insights::PipelineBuilder;
+    ///
+    /// let tracer = PipelineBuilder::from_env()?
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .install_simple();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_env() -> Result<Self, FromEnvError> {
+        env::pipeline_from_env(|name| std::env::var(name).ok())
+    }
 }
 
 impl<C> PipelineBuilder<C> {
@@ -213,6 +466,19 @@ impl<C> PipelineBuilder<C> {
             endpoint: self.endpoint,
             instrumentation_key: self.instrumentation_key,
             sample_rate: self.sample_rate,
+            sampler: self.sampler,
+            adaptive_sampler: self.adaptive_sampler,
+            retry_policy: self.retry_policy,
+            batch_limits: self.batch_limits,
+            storage: self.storage,
+            storage_limits: self.storage_limits,
+            credential: self.credential,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            severity_level_mapping: self.severity_level_mapping,
+            custom_event_names: self.custom_event_names,
+            measurement_policy: self.measurement_policy,
+            exceptions_for_error_spans: self.exceptions_for_error_spans,
         }
     }
 
@@ -242,8 +508,10 @@ impl<C> PipelineBuilder<C> {
         Ok(self)
     }
 
-    /// Set sample rate, which is passed through to Application Insights. It should be a value
-    /// between 0 and 1 and match the rate given to the sampler.
+    /// Set sample rate, which is passed through to Application Insights for spans without their
+    /// own sample rate (see `SAMPLE_RATE`). It should be a value between 0 and 1 and match the
+    /// rate given to the sampler. Use
+    /// `with_application_insights_sampler` to set both at once.
     ///
     /// Default: 1.0
     ///
@@ -263,11 +531,356 @@ impl<C> PipelineBuilder<C> {
         self
     }
 
+    /// Sample traces with an `ApplicationInsightsSampler`, which makes the same decisions as the
+    /// Application Insights SDKs, and pass the sample rate through to Application Insights. It
+    /// should be a value between 0 and 1.
+    ///
+    /// The sampler is installed when the pipeline is built and takes precedence over the sampler of
+    /// the trace config.
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_application_insights_sampler(0.3)
+    ///     .install_simple();
+    /// ```
+    pub fn with_application_insights_sampler(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = None;
+        self.adaptive_sampler = None;
+        self.sampler = Some(PipelineSampler {
+            sampler: Box::new(ApplicationInsightsSampler::new(sample_rate)),
+            // Application Insights expects the sample rate as a percentage.
+            sample_rate: Some(sample_rate * 100.0),
+            from_env: false,
+        });
+        self
+    }
+
+    /// Sample traces with an `AdaptiveSampler`, which adjusts the sample rate to a target number of
+    /// exported telemetry items per second. The exporter counts its items for the sampler and
+    /// passes the sample rate of each span through to Application Insights.
+    ///
+    /// The sampler is installed when the pipeline is built and takes precedence over the sampler of
+    /// the trace config.
+    pub fn with_adaptive_sampler(mut self, sampler: AdaptiveSampler) -> Self {
+        self.sampler = Some(PipelineSampler {
+            sampler: Box::new(sampler.clone()),
+            sample_rate: None,
+            from_env: false,
+        });
+        self.adaptive_sampler = Some(sampler);
+        self
+    }
+
+    /// Set the maximum number of attempts for uploading a batch of telemetry, including the first
+    /// one. Uploads are retried with exponential backoff if Application Insights responds with a
+    /// transient error (e.g. throttling) or the request fails to connect. A value of 1 disables
+    /// retries.
+    ///
+    /// Default: 5
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_max_attempts(3)
+    ///     .install_simple();
+    /// ```
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.retry_policy.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the total time budget for uploading a batch of telemetry, including all retries. No
+    /// further retry is attempted if the next backoff (or the `Retry-After` duration requested
+    /// by Application Insights) would exceed it.
+    ///
+    /// Default: 20 seconds
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_retry_timeout(Duration::from_secs(10))
+    ///     .install_simple();
+    /// ```
+    pub fn with_retry_timeout(mut self, timeout: Duration) -> Self {
+        self.retry_policy.timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of telemetry items sent in one request. Exports with more items
+    /// (note that every span event is a separate item) are split into multiple requests.
+    ///
+    /// Default: 1000
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_max_batch_items(500)
+    ///     .with_max_batch_size(1024 * 1024)
+    ///     .with_max_concurrent_requests(4)
+    ///     .install_simple();
+    /// ```
+    pub fn with_max_batch_items(mut self, max_items: usize) -> Self {
+        self.batch_limits.max_items = max_items.max(1);
+        self
+    }
+
+    /// Set the maximum size in bytes of the telemetry sent in one request (before compression).
+    /// Exports, which exceed this size, are split into multiple requests.
+    ///
+    /// Default: 4 MiB
+    pub fn with_max_batch_size(mut self, max_size: usize) -> Self {
+        self.batch_limits.max_size = max_size;
+        self
+    }
+
+    /// Set the maximum number of requests sent at the same time if an export is split into
+    /// multiple requests.
+    ///
+    /// Default: 1
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.batch_limits.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    /// Enable offline storage in the given directory.
+    ///
+    /// Telemetry, which could not be delivered because Application Insights responded with a
+    /// transient error or rejected the credential (401, 403) or because the request failed to
+    /// connect, is saved to this directory. Saved telemetry is sent again, oldest first, when the
+    /// pipeline is built, every minute afterwards and after every successful export.
+    ///
+    /// Multiple processes may share the same directory. Every saved batch is sent by only one of
+    /// them. Files, which cannot be read, are deleted.
+    ///
+    /// Default: disabled
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_offline_storage("/var/lib/my-application/telemetry")
+    ///     .install_simple();
+    /// ```
+    pub fn with_offline_storage<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.storage = Some(Storage::new(directory.into(), StorageLimits::default()));
+        self
+    }
+
+    /// Set the maximum size of the offline storage in bytes. Telemetry is dropped if saving it
+    /// would exceed this size. Has no effect unless offline storage is enabled.
+    ///
+    /// Default: 50 MiB
+    pub fn with_offline_storage_max_size(mut self, max_size: u64) -> Self {
+        self.storage_limits.max_size = max_size;
+        self
+    }
+
+    /// Set the maximum age of telemetry in the offline storage. Older telemetry is deleted
+    /// instead of sent. Has no effect unless offline storage is enabled.
+    ///
+    /// Default: 48 hours
+    pub fn with_offline_storage_max_age(mut self, max_age: Duration) -> Self {
+        self.storage_limits.max_age = max_age;
+        self
+    }
+
+    /// Authenticate uploads with Azure Active Directory access tokens from the given credential.
+    /// This is required if local authentication is disabled for the Application Insights
+    /// resource, e.g. when the connection string contains `Authorization=AAD`.
+    ///
+    /// Tokens are cached and refreshed shortly before they expire. If Application Insights
+    /// rejects a token, the exporter gets a new one and tries again once. Tokens are only sent to
+    /// the configured endpoint and to redirect targets of Application Insights
+    /// (`*.applicationinsights.azure.com`, `*.services.visualstudio.com`).
+    ///
+    /// Default: no authentication
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// use opentelemetry_application_insights::ManagedIdentityCredential;
+    ///
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_token_credential(ManagedIdentityCredential::new(reqwest::blocking::Client::new()))
+    ///     .install_simple();
+    /// ```
+    pub fn with_token_credential<T: TokenCredential + 'static>(mut self, credential: T) -> Self {
+        self.credential = Some(Box::new(credential));
+        self
+    }
+
+    /// Compress uploads with gzip. Telemetry compresses well, so this reduces the amount of data
+    /// sent to Application Insights considerably at the cost of some CPU time.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: disabled
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_compression(true)
+    ///     .install_simple();
+    /// ```
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = if enabled {
+            Some(self.compression.unwrap_or_default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Set the gzip compression level from 0 (none), 1 (fastest) to 9 (smallest) and enable
+    /// compression.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: 6
+    #[cfg(feature = "compression")]
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.compression = Some(flate2::Compression::new(level.min(9)));
+        self
+    }
+
+    /// Set the function, which determines the severity level of trace telemetry created from span
+    /// events. This replaces the default mapping of the `level` attribute as recorded by
+    /// [`tracing-opentelemetry`].
+    ///
+    /// Default: `TRACE` and `DEBUG` map to Verbose, `INFO` to Information, `WARN` to Warning and
+    /// `ERROR` to Error
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    /// [`tracing-opentelemetry`]: https://crates.io/crates/tracing-opentelemetry
+    ///
+    /// ```no_run
+    /// use opentelemetry_application_insights::SeverityLevel;
+    ///
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_severity_level_mapping(|event| {
+    ///         let severity = event.attributes.iter().find(|kv| kv.key.as_str() == "severity")?;
+    ///         match severity.value.as_str().as_ref() {
+    ///             "fatal" => Some(SeverityLevel::Critical),
+    ///             _ => Some(SeverityLevel::Information),
+    ///         }
+    ///     })
+    ///     .install_simple();
+    /// ```
+    pub fn with_severity_level_mapping<F>(mut self, mapping: F) -> Self
+    where
+        F: Fn(&Event) -> Option<SeverityLevel> + Send + Sync + 'static,
+    {
+        self.severity_level_mapping = Some(SeverityLevelMapping(Arc::new(mapping)));
+        self
+    }
+
+    /// Register names of span events, which are sent as custom events instead of trace telemetry.
+    /// Attributes of these events become custom properties or custom measurements according to
+    /// the measurement policy.
+    ///
+    /// Default: no custom events
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// use opentelemetry::{trace::{Span as _, Tracer as _}, KeyValue};
+    ///
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_custom_event_names(vec!["signup"])
+    ///     .install_simple();
+    ///
+    /// let mut span = tracer.start("register");
+    /// span.add_event("signup".into(), vec![KeyValue::new("plan", "free")]);
+    /// ```
+    pub fn with_custom_event_names<I, T>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Cow<'static, str>>,
+    {
+        self.custom_event_names
+            .extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the policy, which determines the numeric span and event attributes sent as custom
+    /// measurements instead of custom properties.
+    ///
+    /// Measurements are opt-in, so queries on existing custom properties keep working after an
+    /// upgrade. Use `MeasurementPolicy::Numeric` to send all `i64` and `f64` attributes as
+    /// measurements.
+    ///
+    /// Default: `MeasurementPolicy::Disabled`
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// use opentelemetry_application_insights::MeasurementPolicy;
+    ///
+    /// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+    ///     .with_client(reqwest::blocking::Client::new())
+    ///     .with_measurement_policy(MeasurementPolicy::NumericExcept(vec!["thread.id".into()]))
+    ///     .install_simple();
+    /// ```
+    pub fn with_measurement_policy(mut self, policy: MeasurementPolicy) -> Self {
+        self.measurement_policy = policy;
+        self
+    }
+
+    /// Send exception telemetry for spans with status `Error`, which don't have an `exception`
+    /// event. The exception uses the span name as type and the status description as message.
+    ///
+    /// Default: false
+    pub fn with_exceptions_for_error_spans(mut self, enabled: bool) -> Self {
+        self.exceptions_for_error_spans = enabled;
+        self
+    }
+
     /// Assign the SDK config for the exporter pipeline.
     ///
     /// If there is an existing `sdk::Config` in the `PipelineBuilder` the `sdk::Resource`s
     /// are merged and any other parameters are overwritten.
     ///
+    /// A sampler configured with environment variables (see `PipelineBuilder::from_env`) is only
+    /// replaced if the config has a sampler other than the SDK default, i.e. a config, which only
+    /// sets the resource, keeps it.
+    ///
     /// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
     ///
     /// [`reqwest`]: https://crates.io/crates/reqwest
@@ -283,23 +896,34 @@ impl<C> PipelineBuilder<C> {
     ///     ))
     ///     .install_simple();
     /// ```
-    pub fn with_trace_config(self, config: sdk::trace::Config) -> Self {
+    pub fn with_trace_config(mut self, config: sdk::trace::Config) -> Self {
+        // `sdk::trace::Config` always has a sampler. Treat the default one as unset.
+        let has_sampler = format!("{:?}", config.sampler)
+            != format!("{:?}", sdk::trace::Config::default().sampler);
+        if has_sampler
+            && self
+                .sampler
+                .as_ref()
+                .is_some_and(|sampler| sampler.from_env)
+        {
+            self.sampler = None;
+        }
         let config = match config.resource {
             Some(ref resource) => {
                 let merged_resource = match self.config {
-                    Some(base_config) => base_config.resource.map(|r| r.merge(&resource)).unwrap_or(resource.as_ref().clone()),
+                    Some(base_config) => base_config
+                        .resource
+                        .map(|r| r.merge(resource))
+                        .unwrap_or(resource.as_ref().clone()),
                     None => resource.as_ref().clone(),
                 };
 
                 Some(config.with_resource(merged_resource))
-            },
-            None => Some(config)
+            }
+            None => Some(config),
         };
 
-        PipelineBuilder {
-            config: config,
-            ..self
-        }
+        PipelineBuilder { config, ..self }
     }
 
     /// Assign the service name under which to group traces by adding a service.name
@@ -318,10 +942,12 @@ impl<C> PipelineBuilder<C> {
     /// ```
     pub fn with_service_name<T: Into<Cow<'static, str>>>(self, name: T) -> Self {
         let config = self.config.unwrap_or_default();
-        let new_resource = sdk::Resource::new(vec![
-            semcov::resource::SERVICE_NAME.string(name),
-        ]);
-        let merged_resource = config.resource.as_ref().map(|r| r.merge(&new_resource)).unwrap_or(new_resource);
+        let new_resource = sdk::Resource::new(vec![semcov::resource::SERVICE_NAME.string(name)]);
+        let merged_resource = config
+            .resource
+            .as_ref()
+            .map(|r| r.merge(&new_resource))
+            .unwrap_or(new_resource);
         let config = config.with_resource(merged_resource);
 
         PipelineBuilder {
@@ -329,6 +955,19 @@ impl<C> PipelineBuilder<C> {
             ..self
         }
     }
+
+    /// Takes the trace config with the configured sampler and sets the matching sample rate,
+    /// unless a sample rate was set explicitly.
+    fn take_config(&mut self) -> Option<sdk::trace::Config> {
+        let mut config = self.config.take();
+        if let Some(sampler) = self.sampler.take() {
+            config.get_or_insert_with(Default::default).sampler = sampler.sampler;
+            if self.sample_rate.is_none() {
+                self.sample_rate = sampler.sample_rate;
+            }
+        }
+        config
+    }
 }
 
 impl<C> PipelineBuilder<C>
@@ -337,20 +976,44 @@ where
 {
     fn init_exporter(self) -> Exporter<C> {
         let mut exporter = Exporter::new(self.instrumentation_key, self.client);
-        if let Some(endpoint) = self.endpoint {
-            exporter.endpoint = endpoint;
-        }
         if let Some(sample_rate) = self.sample_rate {
             exporter.sample_rate = sample_rate;
         }
+        let storage_limits = self.storage_limits;
+        exporter.storage_limits = storage_limits;
+        let uploader = exporter.uploader_mut();
+        if let Some(endpoint) = self.endpoint {
+            uploader.endpoint = endpoint;
+        }
+        uploader.retry_policy = self.retry_policy;
+        uploader.batch_limits = self.batch_limits;
+        uploader.storage = self.storage.map(|mut storage| {
+            storage.limits = storage_limits;
+            storage
+        });
+        uploader.credential = self.credential.map(CachedCredential::new);
+        #[cfg(feature = "compression")]
+        {
+            uploader.compression = self.compression;
+        }
+        exporter.severity_level_mapping = self.severity_level_mapping;
+        exporter.custom_event_names = self.custom_event_names;
+        exporter.measurement_policy = self.measurement_policy;
+        exporter.exceptions_for_error_spans = self.exceptions_for_error_spans;
+        exporter.adaptive_sampler = self.adaptive_sampler;
 
         exporter
     }
 
     /// Build a configured `TracerProvider` with a simple span processor.
+    ///
+    /// If offline storage is enabled, saved telemetry is sent from a background thread.
     pub fn build_simple(mut self) -> sdk::trace::TracerProvider {
-        let config = self.config.take();
+        let config = self.take_config();
         let exporter = self.init_exporter();
+        if let Some(drain) = exporter.drain_storage() {
+            thread::spawn(move || futures_executor::block_on(drain));
+        }
         let mut builder = sdk::trace::TracerProvider::builder().with_simple_exporter(exporter);
         if let Some(config) = config {
             builder = builder.with_config(config);
@@ -361,9 +1024,14 @@ where
 
     /// Build a configured `TracerProvider` with a batch span processor using the specified
     /// runtime.
+    ///
+    /// If offline storage is enabled, saved telemetry is sent from a task on the runtime.
     pub fn build_batch<R: Runtime>(mut self, runtime: R) -> sdk::trace::TracerProvider {
-        let config = self.config.take();
+        let config = self.take_config();
         let exporter = self.init_exporter();
+        if let Some(drain) = exporter.drain_storage() {
+            runtime.spawn(Box::pin(drain));
+        }
         let mut builder =
             sdk::trace::TracerProvider::builder().with_batch_exporter(exporter, runtime);
         if let Some(config) = config {
@@ -373,6 +1041,28 @@ where
         builder.build()
     }
 
+    /// Build a `TelemetryClient` using the specified runtime to upload buffered telemetry.
+    ///
+    /// The client shares the HTTP client, endpoint, instrumentation key, measurement policy and
+    /// resource of this pipeline. It doesn't install a tracer, so use a second pipeline for that.
+    ///
+    /// If offline storage is enabled, saved telemetry is sent from a task on the runtime.
+    pub fn build_telemetry_client<R: Runtime>(mut self, runtime: R) -> TelemetryClient {
+        let resource = self.take_config().and_then(|config| config.resource);
+        let exporter = self.init_exporter();
+        if let Some(drain) = exporter.drain_storage() {
+            runtime.spawn(Box::pin(drain));
+        }
+        TelemetryClient::new(
+            exporter.instrumentation_key,
+            exporter.client,
+            exporter.uploader,
+            resource.as_deref(),
+            exporter.measurement_policy,
+            runtime,
+        )
+    }
+
     /// Install an Application Insights pipeline with the recommended defaults.
     ///
     /// This registers a global `TracerProvider`. See the `build_simple` function if you don't need
@@ -405,25 +1095,60 @@ where
 /// Application Insights span exporter
 #[derive(Debug)]
 pub struct Exporter<C> {
-    client: C,
-    endpoint: http::Uri,
+    client: Arc<C>,
     instrumentation_key: String,
     sample_rate: f64,
+    uploader: Arc<Uploader>,
+    storage_limits: StorageLimits,
+    severity_level_mapping: Option<SeverityLevelMapping>,
+    custom_event_names: HashSet<Cow<'static, str>>,
+    measurement_policy: MeasurementPolicy,
+    exceptions_for_error_spans: bool,
+    adaptive_sampler: Option<AdaptiveSampler>,
 }
 
 impl<C> Exporter<C> {
     /// Create a new exporter.
     pub fn new(instrumentation_key: String, client: C) -> Self {
         Self {
-            client,
-            endpoint: "https://dc.services.visualstudio.com/v2/track"
-                .try_into()
-                .expect("hardcoded endpoint is valid uri"),
+            client: Arc::new(client),
             instrumentation_key,
             sample_rate: 100.0,
+            uploader: Arc::new(Uploader::new(
+                DEFAULT_ENDPOINT
+                    .try_into()
+                    .expect("hardcoded endpoint is valid uri"),
+            )),
+            storage_limits: StorageLimits::default(),
+            severity_level_mapping: None,
+            custom_event_names: HashSet::new(),
+            measurement_policy: MeasurementPolicy::default(),
+            exceptions_for_error_spans: false,
+            adaptive_sampler: None,
         }
     }
 
+    /// Create a new exporter from a [connection string]. The connection string determines the
+    /// instrumentation key and the ingestion endpoint.
+    ///
+    /// [connection string]: https://docs.microsoft.com/en-us/azure/azure-monitor/app/sdk-connection-string
+    pub fn new_from_connection_string(
+        connection_string: &str,
+        client: C,
+    ) -> Result<Self, ConnectionStringError> {
+        let connection_string: ConnectionString = connection_string.parse()?;
+        let mut exporter = Self::new(connection_string.instrumentation_key().to_string(), client);
+        exporter.uploader_mut().endpoint = connection_string.track_endpoint();
+        Ok(exporter)
+    }
+
+    /// The uploader is only shared once a pipeline builder spawned the offline storage drain for
+    /// the exporter. Users can't change the exporter afterwards.
+    fn uploader_mut(&mut self) -> &mut Uploader {
+        Arc::get_mut(&mut self.uploader)
+            .expect("uploader is not shared before the exporter is built")
+    }
+
     /// Set endpoint used to ingest telemetry. This should consist of scheme and authrity. The
     /// exporter will call `/v2/track` on the specified endpoint.
     ///
@@ -432,12 +1157,13 @@ impl<C> Exporter<C> {
         mut self,
         endpoint: &str,
     ) -> Result<Self, Box<dyn StdError + Send + Sync + 'static>> {
-        self.endpoint = format!("{}/v2/track", endpoint).try_into()?;
+        self.uploader_mut().endpoint = format!("{}/v2/track", endpoint).try_into()?;
         Ok(self)
     }
 
-    /// Set sample rate, which is passed through to Application Insights. It should be a value
-    /// between 0 and 1 and match the rate given to the sampler.
+    /// Set sample rate, which is passed through to Application Insights for spans without their
+    /// own sample rate (see `SAMPLE_RATE`). It should be a value between 0 and 1 and match the
+    /// rate given to the sampler.
     ///
     /// Default: 1.0
     pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
@@ -446,12 +1172,219 @@ impl<C> Exporter<C> {
         self
     }
 
+    /// Set the maximum number of attempts for uploading a batch of telemetry, including the first
+    /// one. A value of 1 disables retries.
+    ///
+    /// Default: 5
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.uploader_mut().retry_policy.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the total time budget for uploading a batch of telemetry, including all retries.
+    ///
+    /// Default: 20 seconds
+    pub fn with_retry_timeout(mut self, timeout: Duration) -> Self {
+        self.uploader_mut().retry_policy.timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of telemetry items sent in one request. Exports with more items
+    /// are split into multiple requests.
+    ///
+    /// Default: 1000
+    pub fn with_max_batch_items(mut self, max_items: usize) -> Self {
+        self.uploader_mut().batch_limits.max_items = max_items.max(1);
+        self
+    }
+
+    /// Set the maximum size in bytes of the telemetry sent in one request (before compression).
+    /// Exports, which exceed this size, are split into multiple requests.
+    ///
+    /// Default: 4 MiB
+    pub fn with_max_batch_size(mut self, max_size: usize) -> Self {
+        self.uploader_mut().batch_limits.max_size = max_size;
+        self
+    }
+
+    /// Set the maximum number of requests sent at the same time if an export is split into
+    /// multiple requests.
+    ///
+    /// Default: 1
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.uploader_mut().batch_limits.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    /// Enable offline storage in the given directory. Telemetry, which could not be delivered
+    /// because of a transient error or a rejected credential, is saved there and sent again after
+    /// later successful exports. Build the exporter with `PipelineBuilder` to also send it in the background.
+    ///
+    /// Default: disabled
+    pub fn with_offline_storage<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.uploader_mut().storage = Some(Storage::new(directory.into(), self.storage_limits));
+        self
+    }
+
+    /// Set the maximum size of the offline storage in bytes. Has no effect unless offline storage
+    /// is enabled.
+    ///
+    /// Default: 50 MiB
+    pub fn with_offline_storage_max_size(mut self, max_size: u64) -> Self {
+        self.storage_limits.max_size = max_size;
+        self.apply_storage_limits()
+    }
+
+    /// Set the maximum age of telemetry in the offline storage. Has no effect unless offline
+    /// storage is enabled.
+    ///
+    /// Default: 48 hours
+    pub fn with_offline_storage_max_age(mut self, max_age: Duration) -> Self {
+        self.storage_limits.max_age = max_age;
+        self.apply_storage_limits()
+    }
+
+    fn apply_storage_limits(mut self) -> Self {
+        let limits = self.storage_limits;
+        if let Some(storage) = self.uploader_mut().storage.as_mut() {
+            storage.limits = limits;
+        }
+        self
+    }
+
+    /// Authenticate uploads with Azure Active Directory access tokens from the given credential.
+    /// Tokens are cached and refreshed shortly before they expire.
+    ///
+    /// Default: no authentication
+    pub fn with_token_credential<T: TokenCredential + 'static>(mut self, credential: T) -> Self {
+        self.uploader_mut().credential = Some(CachedCredential::new(Box::new(credential)));
+        self
+    }
+
+    /// Compress uploads with gzip.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: disabled
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.uploader_mut().compression = if enabled {
+            Some(self.uploader_mut().compression.unwrap_or_default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Set the gzip compression level from 0 (none), 1 (fastest) to 9 (smallest) and enable
+    /// compression.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: 6
+    #[cfg(feature = "compression")]
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.uploader_mut().compression = Some(flate2::Compression::new(level.min(9)));
+        self
+    }
+
+    /// Set the function, which determines the severity level of trace telemetry created from span
+    /// events. This replaces the default mapping of the `level` attribute as recorded by
+    /// `tracing-opentelemetry`.
+    ///
+    /// Default: `TRACE` and `DEBUG` map to Verbose, `INFO` to Information, `WARN` to Warning and
+    /// `ERROR` to Error
+    pub fn with_severity_level_mapping<F>(mut self, mapping: F) -> Self
+    where
+        F: Fn(&Event) -> Option<SeverityLevel> + Send + Sync + 'static,
+    {
+        self.severity_level_mapping = Some(SeverityLevelMapping(Arc::new(mapping)));
+        self
+    }
+
+    /// Register names of span events, which are sent as custom events instead of trace telemetry.
+    /// Attributes of these events become custom properties or custom measurements according to
+    /// the measurement policy.
+    ///
+    /// Default: no custom events
+    pub fn with_custom_event_names<I, T>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Cow<'static, str>>,
+    {
+        self.custom_event_names
+            .extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the policy, which determines the numeric span and event attributes sent as custom
+    /// measurements instead of custom properties.
+    ///
+    /// Measurements are opt-in, so queries on existing custom properties keep working after an
+    /// upgrade. Use `MeasurementPolicy::Numeric` to send all `i64` and `f64` attributes as
+    /// measurements.
+    ///
+    /// Default: `MeasurementPolicy::Disabled`
+    pub fn with_measurement_policy(mut self, policy: MeasurementPolicy) -> Self {
+        self.measurement_policy = policy;
+        self
+    }
+
+    /// Send exception telemetry for spans with status `Error`, which don't have an `exception`
+    /// event. The exception uses the span name as type and the status description as message.
+    ///
+    /// Default: false
+    pub fn with_exceptions_for_error_spans(mut self, enabled: bool) -> Self {
+        self.exceptions_for_error_spans = enabled;
+        self
+    }
+
+    /// Count the exported telemetry items for an `AdaptiveSampler`. Pass a clone of the sampler
+    /// installed in the trace config, so it can adjust the sample rate.
+    ///
+    /// Default: None
+    pub fn with_adaptive_sampler(mut self, sampler: AdaptiveSampler) -> Self {
+        self.adaptive_sampler = Some(sampler);
+        self
+    }
+
     fn create_envelopes(&self, span: SpanData) -> Vec<Envelope> {
         let mut result = Vec::with_capacity(1 + span.events.len());
+        let span_attributes = || span.attributes.iter().filter(|(k, _)| **k != SAMPLE_RATE);
+        // Application Insights expects the sample rate as a percentage.
+        let sample_rate =
+            span_sample_rate(&span).map_or(self.sample_rate, |sample_rate| sample_rate * 100.0);
 
         let (data, tags, name) = match span.span_kind {
+            _ if span.attributes.get(&AVAILABILITY_RUN_LOCATION).is_some() => {
+                let mut data: AvailabilityData = (&span).into();
+                data.measurements = self
+                    .measurement_policy
+                    .apply(span_attributes(), &mut data.properties);
+                let tags = get_tags_for_span(&span);
+                (
+                    Data::Availability(data),
+                    tags,
+                    "Microsoft.ApplicationInsights.Availability",
+                )
+            }
+            _ if matches!(span.attributes.get(&PAGE_VIEW), Some(Value::Bool(true))) => {
+                let mut data: PageViewData = (&span).into();
+                data.measurements = self
+                    .measurement_policy
+                    .apply(span_attributes(), &mut data.properties);
+                let tags = get_tags_for_span(&span);
+                (
+                    Data::PageView(data),
+                    tags,
+                    "Microsoft.ApplicationInsights.PageView",
+                )
+            }
             SpanKind::Server | SpanKind::Consumer => {
-                let data: RequestData = (&span).into();
+                let mut data: RequestData = (&span).into();
+                data.measurements = self
+                    .measurement_policy
+                    .apply(span_attributes(), &mut data.properties);
                 let tags = get_tags_for_span(&span);
                 (
                     Data::Request(data),
@@ -460,7 +1393,10 @@ impl<C> Exporter<C> {
                 )
             }
             SpanKind::Client | SpanKind::Producer | SpanKind::Internal => {
-                let data: RemoteDependencyData = (&span).into();
+                let mut data: RemoteDependencyData = (&span).into();
+                data.measurements = self
+                    .measurement_policy
+                    .apply(span_attributes(), &mut data.properties);
                 let tags = get_tags_for_span(&span);
                 (
                     Data::RemoteDependency(data),
@@ -472,37 +1408,99 @@ impl<C> Exporter<C> {
         result.push(Envelope {
             name: name.into(),
             time: time_to_string(span.start_time).into(),
-            sample_rate: Some(self.sample_rate),
+            sample_rate: Some(sample_rate),
             i_key: Some(self.instrumentation_key.clone().into()),
             tags: Some(tags),
             data: Some(data),
         });
 
         for event in span.events.iter() {
+            let attributes = || event.attributes.iter().map(|kv| (&kv.key, &kv.value));
             let (data, name) = match event.name.as_ref() {
-                "exception" => (
-                    Data::Exception(event.into()),
-                    "Microsoft.ApplicationInsights.Exception",
-                ),
-                _ => (
-                    Data::Message(event.into()),
-                    "Microsoft.ApplicationInsights.Message",
-                ),
+                "exception" => {
+                    let mut data: ExceptionData = event.into();
+                    data.measurements = self
+                        .measurement_policy
+                        .apply(attributes(), &mut data.properties);
+                    (
+                        Data::Exception(data),
+                        "Microsoft.ApplicationInsights.Exception",
+                    )
+                }
+                name if self.custom_event_names.contains(name) => {
+                    let mut data: EventData = event.into();
+                    data.measurements = self
+                        .measurement_policy
+                        .apply(attributes(), &mut data.properties);
+                    (Data::Event(data), "Microsoft.ApplicationInsights.Event")
+                }
+                _ => {
+                    let mut data: MessageData = event.into();
+                    if let Some(mapping) = &self.severity_level_mapping {
+                        data.severity_level = (mapping.0)(event);
+                    }
+                    data.measurements = self
+                        .measurement_policy
+                        .apply(attributes(), &mut data.properties);
+                    (Data::Message(data), "Microsoft.ApplicationInsights.Message")
+                }
             };
             result.push(Envelope {
                 name: name.into(),
                 time: time_to_string(event.timestamp).into(),
-                sample_rate: Some(self.sample_rate),
+                sample_rate: Some(sample_rate),
                 i_key: Some(self.instrumentation_key.clone().into()),
                 tags: Some(get_tags_for_event(&span)),
                 data: Some(data),
             });
         }
 
+        if self.exceptions_for_error_spans
+            && span.status_code == StatusCode::Error
+            && !span.events.iter().any(|event| event.name == "exception")
+        {
+            result.push(Envelope {
+                name: "Microsoft.ApplicationInsights.Exception".into(),
+                time: time_to_string(span.end_time).into(),
+                sample_rate: Some(sample_rate),
+                i_key: Some(self.instrumentation_key.clone().into()),
+                tags: Some(get_tags_for_event(&span)),
+                data: Some(Data::Exception((&span).into())),
+            });
+        }
+
         result
     }
 }
 
+/// Maximum number of batches sent from the offline storage as part of one export. This limits the
+/// time an export takes after a longer outage.
+const MAX_STORED_BATCHES_PER_EXPORT: usize = 10;
+
+type SeverityLevelFn = dyn Fn(&Event) -> Option<SeverityLevel> + Send + Sync;
+
+/// Determines the severity level of trace telemetry created from span events.
+#[derive(Clone)]
+struct SeverityLevelMapping(Arc<SeverityLevelFn>);
+
+impl fmt::Debug for SeverityLevelMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SeverityLevelMapping")
+    }
+}
+
+impl<C: HttpClient + 'static> Exporter<C> {
+    /// Task, which sends telemetry from the offline storage at startup and periodically until the
+    /// exporter is dropped. `None` if offline storage is disabled.
+    fn drain_storage(&self) -> Option<impl Future<Output = ()> + Send + 'static> {
+        self.uploader.storage.as_ref()?;
+        Some(uploader::drain_storage(
+            self.client.clone(),
+            Arc::downgrade(&self.uploader),
+        ))
+    }
+}
+
 #[async_trait]
 impl<C> SpanExporter for Exporter<C>
 where
@@ -514,12 +1512,43 @@ where
             .into_iter()
             .flat_map(|span| self.create_envelopes(span))
             .collect();
+        if let Some(sampler) = &self.adaptive_sampler {
+            sampler.record_items(envelopes.len() as u64, Instant::now());
+        }
 
-        uploader::send(&self.client, &self.endpoint, envelopes).await
+        self.uploader.send(&*self.client, envelopes).await?;
+        // The spans were delivered, so the export succeeded even if sending saved telemetry fails.
+        if let Err(err) = self
+            .uploader
+            .send_stored(&*self.client, MAX_STORED_BATCHES_PER_EXPORT)
+            .await
+        {
+            global::handle_error(TraceError::from(err));
+        }
+
+        Ok(())
     }
 }
 
 /// Errors that occurred during span export.
+///
+/// The exporter returns them wrapped in a `TraceError::ExportFailed`:
+///
+/// ```
+/// use opentelemetry::trace::TraceError;
+/// use opentelemetry_application_insights::Error;
+///
+/// fn handle_export_error(error: &TraceError) {
+///     if let TraceError::ExportFailed(error) = error {
+///         let error: &dyn std::error::Error = error.as_ref();
+///         match error.downcast_ref::<Error>() {
+///             Some(Error::UploadStatus { status: 439, .. }) => eprintln!("quota exceeded"),
+///             Some(Error::UploadStatus { retryable: false, .. }) => eprintln!("telemetry lost"),
+///             _ => eprintln!("{}", error),
+///         }
+///     }
+/// }
+/// ```
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -545,8 +1574,97 @@ pub enum Error {
     UploadConnection(Box<dyn StdError + Send + Sync + 'static>),
 
     /// Application Insights returned at least one error for the reported telemetry data.
+    ///
+    /// Note: This error is no longer returned by the exporter. Upload errors are reported as
+    /// `UploadStatus`.
+    #[deprecated(note = "upload errors are reported as `Error::UploadStatus`")]
     #[error("upload failed with {0}")]
     Upload(String),
+
+    /// Application Insights responded with an error status or did not accept all telemetry
+    /// items. Transient errors are only reported after all retries failed.
+    #[error(
+        "upload failed with status {status}{}{}",
+        if *.retryable { " (retry possible)" } else { "" },
+        describe_transmission(.transmission)
+    )]
+    UploadStatus {
+        /// HTTP status of the last response. 206 if some items were rejected, while all other
+        /// items were accepted eventually.
+        status: u16,
+
+        /// Whether sending the telemetry, which was not accepted, again later may succeed.
+        retryable: bool,
+
+        /// Time Application Insights asked to wait before sending again (`Retry-After` header).
+        retry_after: Option<Duration>,
+
+        /// Results for individual telemetry items, if Application Insights returned any. Indices
+        /// refer to the items as they were sent in the first attempt.
+        transmission: Option<Transmission>,
+    },
+
+    /// Telemetry could not be delivered because of a transient error or a rejected credential and
+    /// was saved to the offline storage. It will be sent again later.
+    #[error("{error}. {items} items were saved to offline storage and will be sent later")]
+    SavedToOfflineStorage {
+        /// Number of saved telemetry items.
+        items: usize,
+
+        /// Error, which prevented the delivery.
+        error: Box<Error>,
+    },
+
+    /// An export was split into multiple upload requests and more than one of them failed.
+    #[error("{} of {requests} upload requests failed: {}", .errors.len(), describe_errors(.errors))]
+    UploadRequests {
+        /// Number of upload requests.
+        requests: usize,
+
+        /// Errors of the failed requests.
+        errors: Vec<Error>,
+    },
+
+    /// Application Insights redirected the upload without a valid location, in a loop or too
+    /// many times.
+    #[error(
+        "following upload redirect failed (location: {})",
+        .location.as_deref().unwrap_or("<none>")
+    )]
+    UploadRedirect {
+        /// Location of the last redirect.
+        location: Option<String>,
+    },
+
+    /// Reading or writing the offline storage failed. Telemetry saved in the offline storage may
+    /// be sent again later.
+    #[error("offline storage failed with {0}")]
+    OfflineStorage(std::io::Error),
+
+    /// Could not get an access token from the configured token credential. Telemetry reporting
+    /// failed because of this.
+    #[error("getting access token failed with {0}")]
+    Authentication(Box<dyn StdError + Send + Sync + 'static>),
+}
+
+fn describe_transmission(transmission: &Option<Transmission>) -> String {
+    match transmission {
+        Some(transmission) if !transmission.errors.is_empty() => format!(
+            ". {} of {} items were accepted: {}",
+            transmission.items_accepted,
+            transmission.items_received,
+            uploader::describe_items(&transmission.errors)
+        ),
+        _ => String::new(),
+    }
+}
+
+fn describe_errors(errors: &[Error]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
 }
 
 impl ExportError for Error {
@@ -555,6 +1673,60 @@ impl ExportError for Error {
     }
 }
 
+impl From<&SpanData> for AvailabilityData {
+    fn from(span: &SpanData) -> AvailabilityData {
+        let mut properties = attrs_to_properties(&span.attributes, span.resource.clone());
+        if let Some(properties) = properties.as_mut() {
+            properties.remove(&AVAILABILITY_RUN_LOCATION.as_str().into());
+        }
+        AvailabilityData {
+            ver: 2,
+            id: span_id_to_string(span.span_context.span_id()).into(),
+            name: span.name.clone().into(),
+            duration: duration_to_string(
+                span.end_time
+                    .duration_since(span.start_time)
+                    .unwrap_or_default(),
+            ),
+            success: span.status_code != StatusCode::Error,
+            run_location: span
+                .attributes
+                .get(&AVAILABILITY_RUN_LOCATION)
+                .map(Into::into),
+            message: Some(span.status_message.clone())
+                .filter(|x| !x.is_empty())
+                .map(Into::into),
+            properties: properties.filter(|x| !x.is_empty()),
+            measurements: None,
+        }
+    }
+}
+
+impl From<&SpanData> for PageViewData {
+    fn from(span: &SpanData) -> PageViewData {
+        let mut properties = attrs_to_properties(&span.attributes, span.resource.clone());
+        if let Some(properties) = properties.as_mut() {
+            properties.remove(&PAGE_VIEW.as_str().into());
+        }
+        PageViewData {
+            ver: 2,
+            name: span.name.clone().into(),
+            url: span
+                .attributes
+                .get(&semcov::trace::HTTP_URL)
+                .map(Into::into),
+            duration: Some(duration_to_string(
+                span.end_time
+                    .duration_since(span.start_time)
+                    .unwrap_or_default(),
+            )),
+            id: Some(span_id_to_string(span.span_context.span_id()).into()),
+            properties: properties.filter(|x| !x.is_empty()),
+            measurements: None,
+        }
+    }
+}
+
 impl From<&SpanData> for RequestData {
     fn from(span: &SpanData) -> RequestData {
         let mut data = RequestData {
@@ -572,6 +1744,7 @@ impl From<&SpanData> for RequestData {
             source: None,
             url: None,
             properties: attrs_to_properties(&span.attributes, span.resource.clone()),
+            measurements: None,
         };
 
         if let Some(method) = span.attributes.get(&semcov::trace::HTTP_METHOD) {
@@ -588,6 +1761,13 @@ impl From<&SpanData> for RequestData {
             data.response_code = status_code.into();
         }
 
+        if !span.status_message.is_empty() {
+            data.properties.get_or_insert_with(Properties::new).insert(
+                STATUS_DESCRIPTION.into(),
+                span.status_message.clone().into(),
+            );
+        }
+
         if let Some(url) = span.attributes.get(&semcov::trace::HTTP_URL) {
             data.url = Some(url.into());
         } else if let Some(target) = span.attributes.get(&semcov::trace::HTTP_TARGET) {
@@ -638,12 +1818,20 @@ impl From<&SpanData> for RemoteDependencyData {
             target: None,
             type_: None,
             properties: attrs_to_properties(&span.attributes, span.resource.clone()),
+            measurements: None,
         };
 
         if let Some(status_code) = span.attributes.get(&semcov::trace::HTTP_STATUS_CODE) {
             data.result_code = Some(status_code.into());
         }
 
+        if !span.status_message.is_empty() {
+            data.properties.get_or_insert_with(Properties::new).insert(
+                STATUS_DESCRIPTION.into(),
+                span.status_message.clone().into(),
+            );
+        }
+
         if let Some(url) = span.attributes.get(&semcov::trace::HTTP_URL) {
             data.data = Some(url.into());
         } else if let Some(statement) = span.attributes.get(&semcov::trace::DB_STATEMENT) {
@@ -689,6 +1877,29 @@ impl From<&SpanData> for RemoteDependencyData {
     }
 }
 
+impl From<&SpanData> for ExceptionData {
+    fn from(span: &SpanData) -> ExceptionData {
+        let exception = ExceptionDetails {
+            id: None,
+            outer_id: None,
+            type_name: span.name.clone().into(),
+            message: Some(span.status_message.clone())
+                .filter(|x| !x.is_empty())
+                .map(Into::into)
+                .unwrap_or_else(|| "<no message>".into()),
+            has_full_stack: None,
+            stack: None,
+            parsed_stack: None,
+        };
+        ExceptionData {
+            ver: 2,
+            exceptions: vec![exception],
+            properties: None,
+            measurements: None,
+        }
+    }
+}
+
 impl From<&Event> for ExceptionData {
     fn from(event: &Event) -> ExceptionData {
         let mut attrs: HashMap<&Key, &Value> = event
@@ -696,22 +1907,10 @@ impl From<&Event> for ExceptionData {
             .iter()
             .map(|kv| (&kv.key, &kv.value))
             .collect();
-        let exception = ExceptionDetails {
-            type_name: attrs
-                .remove(&semcov::trace::EXCEPTION_TYPE)
-                .map(Into::into)
-                .unwrap_or_else(|| "<no type>".into()),
-            message: attrs
-                .remove(&semcov::trace::EXCEPTION_MESSAGE)
-                .map(Into::into)
-                .unwrap_or_else(|| "<no message>".into()),
-            stack: attrs
-                .remove(&semcov::trace::EXCEPTION_STACKTRACE)
-                .map(Into::into),
-        };
+        let exceptions = take_exception_details(&mut attrs);
         ExceptionData {
             ver: 2,
-            exceptions: vec![exception],
+            exceptions,
             properties: Some(
                 attrs
                     .iter()
@@ -719,6 +1918,25 @@ impl From<&Event> for ExceptionData {
                     .collect(),
             )
             .filter(|x: &Properties| !x.is_empty()),
+            measurements: None,
+        }
+    }
+}
+
+impl From<&Event> for EventData {
+    fn from(event: &Event) -> EventData {
+        EventData {
+            ver: 2,
+            name: event.name.clone().into(),
+            properties: Some(
+                event
+                    .attributes
+                    .iter()
+                    .map(|kv| (kv.key.as_str().into(), (&kv.value).into()))
+                    .collect(),
+            )
+            .filter(|x: &Properties| !x.is_empty()),
+            measurements: None,
         }
     }
 }
@@ -732,6 +1950,11 @@ impl From<&Event> for MessageData {
             } else {
                 event.name.clone().into_owned().into()
             },
+            severity_level: event
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == "level")
+                .and_then(|kv| level_to_severity_level(&kv.value.as_str())),
             properties: Some(
                 event
                     .attributes
@@ -740,6 +1963,257 @@ impl From<&Event> for MessageData {
                     .collect(),
             )
             .filter(|x: &Properties| !x.is_empty()),
+            measurements: None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{http_client::tests::RecordingClient, storage::tests::TempDir};
+    use opentelemetry::{
+        trace::{
+            Span as _, SpanContext, SpanId, TraceContextExt as _, TraceId, TraceState, Tracer as _,
+            TRACE_FLAG_SAMPLED,
+        },
+        Context,
+    };
+    use std::time::{Duration, SystemTime};
+    use test_case::test_case;
+
+    /// Tracer provider, which exports spans with a simple span processor.
+    pub(crate) fn tracer_provider(
+        exporter: Exporter<RecordingClient>,
+    ) -> sdk::trace::TracerProvider {
+        sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .build()
+    }
+
+    fn export_error_span(exceptions_for_error_spans: bool, record_exception: bool) -> Vec<String> {
+        let client = RecordingClient::default();
+        let tracer_provider = tracer_provider(
+            Exporter::new("key".into(), client.clone())
+                .with_exceptions_for_error_spans(exceptions_for_error_spans),
+        );
+        let tracer = tracer_provider.get_tracer("test", None);
+        let mut span = tracer.start("query");
+        if record_exception {
+            span.add_event("exception".into(), vec![]);
+        }
+        span.set_status(StatusCode::Error, "timed out".into());
+        span.end();
+        // Shutting down the provider waits for the simple span processor to export all spans.
+        drop(tracer_provider);
+
+        let envelopes = client.envelopes();
+        assert_eq!(
+            "timed out",
+            envelopes[0]["data"]["baseData"]["properties"][STATUS_DESCRIPTION]
+        );
+        if let Some(exception) = envelopes.get(1) {
+            assert_eq!(
+                envelopes[0]["data"]["baseData"]["id"],
+                exception["tags"]["ai.operation.parentId"]
+            );
+        }
+        envelopes
+            .iter()
+            .skip(1)
+            .map(|envelope| {
+                let exception = &envelope["data"]["baseData"]["exceptions"][0];
+                format!("{}: {}", exception["typeName"], exception["message"])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn offline_storage_limits_in_any_order() {
+        let exporter = new_pipeline("key".into())
+            .with_client(RecordingClient::default())
+            .with_offline_storage_max_size(10)
+            .with_offline_storage("telemetry")
+            .with_offline_storage_max_age(Duration::from_secs(60))
+            .init_exporter();
+        let limits = exporter.uploader.storage.as_ref().unwrap().limits;
+        assert_eq!(10, limits.max_size);
+        assert_eq!(Duration::from_secs(60), limits.max_age);
+
+        let exporter = Exporter::new("key".into(), RecordingClient::default())
+            .with_offline_storage_max_size(10)
+            .with_offline_storage("telemetry");
+        assert_eq!(
+            10,
+            exporter.uploader.storage.as_ref().unwrap().limits.max_size
+        );
+    }
+
+    #[test]
+    fn application_insights_sampler_in_any_order() {
+        let mut builder = new_pipeline("key".into())
+            .with_client(RecordingClient::default())
+            .with_application_insights_sampler(0.3)
+            .with_trace_config(
+                sdk::trace::Config::default().with_sampler(sdk::trace::Sampler::AlwaysOn),
+            );
+        let config = builder.take_config().unwrap();
+        assert!(format!("{:?}", config.sampler).contains("ApplicationInsightsSampler"));
+        assert_eq!(30.0, builder.init_exporter().sample_rate);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn offline_storage_is_sent_at_startup() {
+        let dir = TempDir::new();
+        Storage::new(dir.0.clone(), StorageLimits::default())
+            .store(b"[{\"name\":\"stored\"}]")
+            .unwrap();
+        let client = RecordingClient::default();
+        let _tracer_provider = new_pipeline("key".into())
+            .with_client(client.clone())
+            .with_offline_storage(dir.0.clone())
+            .build_batch(opentelemetry::runtime::Tokio);
+
+        for _ in 0..100 {
+            if !client.envelopes().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!("stored", client.envelopes()[0]["name"]);
+    }
+
+    #[test_case(false, false, &[]                                   ; "disabled")]
+    #[test_case(true,  false, &[r#""query": "timed out""#]          ; "synthesized")]
+    #[test_case(true,  true,  &[r#""<no type>": "<no message>""#]   ; "recorded exception")]
+    fn exceptions_for_error_spans(enabled: bool, record_exception: bool, expected: &[&str]) {
+        assert_eq!(expected, export_error_span(enabled, record_exception));
+    }
+
+    #[test]
+    fn availability() {
+        let client = RecordingClient::default();
+        let tracer_provider = sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(Exporter::new("key".into(), client.clone()))
+            .build();
+        let tracer = tracer_provider.get_tracer("test", None);
+        track_availability(
+            &tracer,
+            AvailabilityResult {
+                name: "health".into(),
+                run_location: "west-europe".into(),
+                success: false,
+                message: Some("connection refused".into()),
+                start_time: SystemTime::UNIX_EPOCH,
+                duration: Duration::from_millis(1500),
+            },
+        );
+        drop(tracer_provider);
+
+        let envelopes = client.envelopes();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(
+            "Microsoft.ApplicationInsights.Availability",
+            envelopes[0]["name"]
+        );
+        assert_eq!("1970-01-01T00:00:00.000Z", envelopes[0]["time"]);
+        assert_eq!("AvailabilityData", envelopes[0]["data"]["baseType"]);
+        let data = &envelopes[0]["data"]["baseData"];
+        assert_eq!("health", data["name"]);
+        assert_eq!("0.00:00:01.500000", data["duration"]);
+        assert_eq!(false, data["success"]);
+        assert_eq!("west-europe", data["runLocation"]);
+        assert_eq!("connection refused", data["message"]);
+        assert!(data.get("properties").is_none());
+    }
+
+    #[test_case(true,  "Microsoft.ApplicationInsights.PageView" ; "marked")]
+    #[test_case(false, "Microsoft.ApplicationInsights.Request"  ; "not marked")]
+    fn page_views(page_view: bool, expected_name: &str) {
+        let client = RecordingClient::default();
+        let tracer_provider = sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(Exporter::new("key".into(), client.clone()))
+            .build();
+        let tracer = tracer_provider.get_tracer("test", None);
+        let start_time = SystemTime::UNIX_EPOCH;
+        let mut span = tracer
+            .span_builder("home")
+            .with_kind(SpanKind::Server)
+            .with_start_time(start_time)
+            .with_attributes(vec![
+                PAGE_VIEW.bool(page_view),
+                semcov::trace::HTTP_URL.string("https://example.com/"),
+            ])
+            .start(&tracer);
+        span.end_with_timestamp(start_time + Duration::from_millis(250));
+        drop(tracer_provider);
+
+        let envelopes = client.envelopes();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(expected_name, envelopes[0]["name"]);
+        if page_view {
+            let data = &envelopes[0]["data"]["baseData"];
+            assert_eq!("home", data["name"]);
+            assert_eq!("https://example.com/", data["url"]);
+            assert_eq!("0.00:00:00.250000", data["duration"]);
+            assert_eq!(16, data["id"].as_str().unwrap().len());
+            assert_eq!("https://example.com/", data["properties"]["http.url"]);
+            assert!(data["properties"].get(PAGE_VIEW.as_str()).is_none());
+        }
+    }
+
+    #[test]
+    fn adaptive_sample_rate() {
+        let client = RecordingClient::default();
+        let tracer_provider = sdk::trace::TracerProvider::builder()
+            .with_config(
+                sdk::trace::config()
+                    .with_sampler(AdaptiveSampler::new(10.0).with_initial_sample_rate(1.0)),
+            )
+            .with_simple_exporter(Exporter::new("key".into(), client.clone()).with_sample_rate(0.5))
+            .build();
+        let tracer = tracer_provider.get_tracer("test", None);
+        let mut span = tracer.start("request");
+        span.add_event("started".into(), vec![]);
+        // The child span gets the sample rate from the trace state.
+        let cx = Context::current_with_span(span);
+        tracer.start_with_context("query", cx.clone()).end();
+        cx.span().end();
+        drop(tracer_provider);
+
+        let envelopes = client.envelopes();
+        assert_eq!(3, envelopes.len());
+        for envelope in envelopes {
+            assert_eq!(100.0, envelope["sampleRate"]);
+            assert!(envelope["data"]["baseData"].get("properties").is_none());
+            assert!(envelope["data"]["baseData"].get("measurements").is_none());
         }
     }
+
+    #[test_case(None,         50.0 ; "configured")]
+    #[test_case(Some("th:c"), 25.0 ; "trace state")]
+    fn sample_rate_from_trace_state(ot: Option<&str>, expected: f64) {
+        let client = RecordingClient::default();
+        let tracer_provider = sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(Exporter::new("key".into(), client.clone()).with_sample_rate(0.5))
+            .build();
+        let tracer = tracer_provider.get_tracer("test", None);
+        let trace_state = match ot {
+            Some(ot) => TraceState::from_key_value(vec![("ot", ot)]).unwrap(),
+            None => TraceState::default(),
+        };
+        let parent = Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from_u128(1),
+            SpanId::from_u64(1),
+            TRACE_FLAG_SAMPLED,
+            true,
+            trace_state,
+        ));
+        tracer.start_with_context("request", parent).end();
+        drop(tracer_provider);
+
+        let envelopes = client.envelopes();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(expected, envelopes[0]["sampleRate"]);
+    }
 }
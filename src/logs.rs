@@ -0,0 +1,441 @@
+use crate::{
+    auth::{CachedCredential, TokenCredential},
+    batch::{BatchConfig, BatchSender},
+    connection_string::{ConnectionString, ConnectionStringError},
+    convert::{level_to_severity_level, time_to_string},
+    models::{Data, Envelope, MessageData, Properties},
+    tags::get_tags_for_span_context,
+    uploader::{RetryPolicy, Uploader},
+    HttpClient, DEFAULT_ENDPOINT,
+};
+use log::{LevelFilter, Log, Metadata, Record};
+use opentelemetry::{runtime::Runtime, trace::TraceContextExt as _, Context};
+use opentelemetry_semantic_conventions as semcov;
+use std::{
+    convert::TryInto,
+    error::Error as StdError,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Target prefix of records logged by this crate.
+const OWN_TARGET: &str = "opentelemetry_application_insights";
+
+/// Create a new Application Insights log pipeline builder
+pub fn new_log_pipeline(instrumentation_key: String) -> LogPipelineBuilder<()> {
+    LogPipelineBuilder {
+        client: (),
+        endpoint: None,
+        instrumentation_key,
+        max_level: LevelFilter::Info,
+        batch_config: BatchConfig::default(),
+        retry_policy: RetryPolicy::default(),
+        credential: None,
+        #[cfg(feature = "compression")]
+        compression: None,
+    }
+}
+
+/// Create a new Application Insights log pipeline builder from a [connection string]. The
+/// connection string determines the instrumentation key and the ingestion endpoint.
+///
+/// If the connection string contains `Authorization=AAD`, set a credential with
+/// `with_token_credential`. Uploads are rejected otherwise.
+///
+/// [connection string]: https://docs.microsoft.com/en-us/azure/azure-monitor/app/sdk-connection-string
+pub fn new_log_pipeline_from_connection_string(
+    connection_string: &str,
+) -> Result<LogPipelineBuilder<()>, ConnectionStringError> {
+    let connection_string: ConnectionString = connection_string.parse()?;
+    let mut builder = new_log_pipeline(connection_string.instrumentation_key().to_string());
+    builder.endpoint = Some(connection_string.track_endpoint());
+    Ok(builder)
+}
+
+/// Application Insights log pipeline builder
+#[derive(Debug)]
+pub struct LogPipelineBuilder<C> {
+    client: C,
+    endpoint: Option<http::Uri>,
+    instrumentation_key: String,
+    max_level: LevelFilter,
+    batch_config: BatchConfig,
+    retry_policy: RetryPolicy,
+    credential: Option<Box<dyn TokenCredential>>,
+    #[cfg(feature = "compression")]
+    compression: Option<flate2::Compression>,
+}
+
+impl<C> LogPipelineBuilder<C> {
+    /// Set HTTP client, which the logger will use to send telemetry to Application Insights.
+    ///
+    /// Use this to set an HTTP client which fits your async runtime.
+    pub fn with_client<NC>(self, client: NC) -> LogPipelineBuilder<NC> {
+        LogPipelineBuilder {
+            client,
+            endpoint: self.endpoint,
+            instrumentation_key: self.instrumentation_key,
+            max_level: self.max_level,
+            batch_config: self.batch_config,
+            retry_policy: self.retry_policy,
+            credential: self.credential,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+        }
+    }
+
+    /// Set endpoint used to ingest telemetry. This should consist of scheme and authrity. The
+    /// logger will call `/v2/track` on the specified endpoint.
+    ///
+    /// Default: https://dc.services.visualstudio.com
+    pub fn with_endpoint(
+        mut self,
+        endpoint: &str,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync + 'static>> {
+        self.endpoint = Some(format!("{}/v2/track", endpoint).try_into()?);
+        Ok(self)
+    }
+
+    /// Set the most verbose level, which is sent to Application Insights.
+    ///
+    /// Default: `LevelFilter::Info`
+    pub fn with_max_level(mut self, max_level: LevelFilter) -> Self {
+        self.max_level = max_level;
+        self
+    }
+
+    /// Set the maximum number of records buffered before they are uploaded. Records logged while
+    /// the buffer is full are dropped.
+    ///
+    /// Default: 2048
+    pub fn with_max_queue_size(mut self, max_queue_size: usize) -> Self {
+        self.batch_config.max_queue_size = max_queue_size.max(1);
+        self
+    }
+
+    /// Set the interval in which buffered records are uploaded.
+    ///
+    /// Default: 5 seconds
+    pub fn with_scheduled_delay(mut self, scheduled_delay: Duration) -> Self {
+        self.batch_config.scheduled_delay = scheduled_delay;
+        self
+    }
+
+    /// Set the number of buffered records, which triggers an upload before the scheduled delay
+    /// passed.
+    ///
+    /// Default: 512
+    pub fn with_max_export_batch_size(mut self, max_export_batch_size: usize) -> Self {
+        self.batch_config.max_export_batch_size = max_export_batch_size.max(1);
+        self
+    }
+
+    /// Set the maximum number of attempts for uploading a batch of records, including the first
+    /// one. A value of 1 disables retries.
+    ///
+    /// Default: 5
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.retry_policy.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the total time budget for uploading a batch of records, including all retries.
+    ///
+    /// Default: 20 seconds
+    pub fn with_retry_timeout(mut self, timeout: Duration) -> Self {
+        self.retry_policy.timeout = timeout;
+        self
+    }
+
+    /// Authenticate uploads with Azure Active Directory access tokens from the given credential.
+    /// This is required if local authentication is disabled for the Application Insights
+    /// resource.
+    ///
+    /// Default: no authentication
+    pub fn with_token_credential<T: TokenCredential + 'static>(mut self, credential: T) -> Self {
+        self.credential = Some(Box::new(credential));
+        self
+    }
+
+    /// Compress uploads with gzip.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: disabled
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = if enabled {
+            Some(self.compression.unwrap_or_default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Set the gzip compression level and enable compression. See
+    /// `PipelineBuilder::with_compression_level`.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: 6
+    #[cfg(feature = "compression")]
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.compression = Some(flate2::Compression::new(level.min(9)));
+        self
+    }
+}
+
+impl<C> LogPipelineBuilder<C>
+where
+    C: HttpClient + 'static,
+{
+    /// Build a `Logger`, which uploads records in batches from a background task spawned on the
+    /// specified runtime.
+    pub fn build_batch<R: Runtime>(self, runtime: R) -> Logger {
+        let mut uploader = Uploader::new(
+            DEFAULT_ENDPOINT
+                .try_into()
+                .expect("hardcoded endpoint is valid uri"),
+        );
+        if let Some(endpoint) = self.endpoint {
+            uploader.endpoint = endpoint;
+        }
+        uploader.retry_policy = self.retry_policy;
+        uploader.credential = self.credential.map(CachedCredential::new);
+        #[cfg(feature = "compression")]
+        {
+            uploader.compression = self.compression;
+        }
+
+        Logger {
+            instrumentation_key: self.instrumentation_key,
+            max_level: self.max_level,
+            sender: BatchSender::spawn(
+                Arc::new(self.client),
+                Arc::new(uploader),
+                self.batch_config,
+                runtime,
+            ),
+        }
+    }
+
+    /// Install an Application Insights logger with the specified runtime.
+    ///
+    /// This registers the logger globally with the `log` crate. See the `build_batch` function if
+    /// you don't need that. Call `log::logger().flush()` before your program exits to upload the
+    /// remaining records (this example requires the **logs**, **reqwest-client** and
+    /// **opentelemetry/rt-tokio** features).
+    ///
+    /// ```no_run
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let instrumentation_key = std::env::var("INSTRUMENTATION_KEY").unwrap();
+    ///     opentelemetry_application_insights::new_log_pipeline(instrumentation_key)
+    ///         .with_client(reqwest::Client::new())
+    ///         .install_batch(opentelemetry::runtime::Tokio)
+    ///         .expect("no other logger is installed");
+    ///
+    ///     log::info!("hello world");
+    ///
+    ///     log::logger().flush();
+    /// }
+    /// ```
+    pub fn install_batch<R: Runtime>(self, runtime: R) -> Result<(), log::SetLoggerError> {
+        let logger = self.build_batch(runtime);
+        let max_level = logger.max_level;
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+/// Logger, which sends records of the `log` crate to Application Insights as trace telemetry.
+///
+/// Records are correlated with the current OpenTelemetry span. Records of this crate are ignored,
+/// so uploads can't log themselves. Consider limiting the level of your HTTP client's crates as
+/// well.
+#[derive(Debug)]
+pub struct Logger {
+    instrumentation_key: String,
+    max_level: LevelFilter,
+    sender: BatchSender,
+}
+
+impl Logger {
+    fn create_envelope(&self, record: &Record<'_>) -> Envelope {
+        let mut properties = Properties::new();
+        properties.insert("level".into(), record.level().as_str().into());
+        properties.insert("target".into(), record.target().into());
+        if let Some(module_path) = record.module_path() {
+            properties.insert(
+                semcov::trace::CODE_NAMESPACE.as_str().into(),
+                module_path.into(),
+            );
+        }
+        if let Some(file) = record.file() {
+            properties.insert(semcov::trace::CODE_FILEPATH.as_str().into(), file.into());
+        }
+        if let Some(line) = record.line() {
+            properties.insert(
+                semcov::trace::CODE_LINENO.as_str().into(),
+                line.to_string().into(),
+            );
+        }
+
+        let cx = Context::current();
+        let span = cx.span();
+        let span_context = span.span_context();
+        Envelope {
+            name: "Microsoft.ApplicationInsights.Message".into(),
+            time: time_to_string(SystemTime::now()).into(),
+            sample_rate: None,
+            i_key: Some(self.instrumentation_key.clone().into()),
+            tags: Some(span_context)
+                .filter(|span_context| span_context.is_valid())
+                .map(get_tags_for_span_context),
+            data: Some(Data::Message(MessageData {
+                ver: 2,
+                message: record.args().to_string().into(),
+                severity_level: level_to_severity_level(record.level().as_str()),
+                properties: Some(properties),
+                measurements: None,
+            })),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.max_level && !metadata.target().starts_with(OWN_TARGET)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            // Records are dropped if the buffer is full.
+            self.sender.send(self.create_envelope(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.sender.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_client::tests::RecordingClient, StaticTokenCredential};
+    use opentelemetry::trace::{SpanContext, SpanId, TraceId, TraceState};
+
+    fn logger(client: &RecordingClient) -> Logger {
+        new_log_pipeline("key".into())
+            .with_client(client.clone())
+            .with_max_level(LevelFilter::Debug)
+            .build_batch(opentelemetry::runtime::Tokio)
+    }
+
+    fn log(logger: &Logger, level: log::Level, target: &str, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .module_path(Some("app::module"))
+                .file(Some("src/module.rs"))
+                .line(Some(42))
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sends_records_as_messages() {
+        let client = RecordingClient::default();
+        let logger = logger(&client);
+        log(&logger, log::Level::Warn, "app", "hello");
+        log(&logger, log::Level::Trace, "app", "too verbose");
+        log(&logger, log::Level::Error, module_path!(), "own record");
+        logger.flush();
+
+        let envelopes = client.envelopes();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(
+            "Microsoft.ApplicationInsights.Message",
+            envelopes[0]["name"]
+        );
+        assert!(envelopes[0].get("tags").is_none());
+        let data = &envelopes[0]["data"]["baseData"];
+        assert_eq!("hello", data["message"]);
+        assert_eq!("Warning", data["severityLevel"]);
+        assert_eq!("WARN", data["properties"]["level"]);
+        assert_eq!("app", data["properties"]["target"]);
+        assert_eq!("app::module", data["properties"]["code.namespace"]);
+        assert_eq!("src/module.rs", data["properties"]["code.filepath"]);
+        assert_eq!("42", data["properties"]["code.lineno"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn correlates_records_with_current_span() {
+        let client = RecordingClient::default();
+        let logger = logger(&client);
+        let span_context = SpanContext::new(
+            TraceId::from_u128(314),
+            SpanId::from_u64(42),
+            0,
+            false,
+            TraceState::default(),
+        );
+        {
+            let _guard = Context::current()
+                .with_remote_span_context(span_context)
+                .attach();
+            log(&logger, log::Level::Info, "app", "inside span");
+        }
+        logger.flush();
+
+        let envelopes = client.envelopes();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(
+            "0000000000000000000000000000013a",
+            envelopes[0]["tags"]["ai.operation.id"]
+        );
+        assert_eq!(
+            "000000000000002a",
+            envelopes[0]["tags"]["ai.operation.parentId"]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn authenticates_uploads() {
+        let client = RecordingClient::default();
+        let logger =
+            new_log_pipeline_from_connection_string("InstrumentationKey=key;Authorization=AAD")
+                .unwrap()
+                .with_client(client.clone())
+                .with_token_credential(StaticTokenCredential::new("token"))
+                .build_batch(opentelemetry::runtime::Tokio);
+        log(&logger, log::Level::Info, "app", "hello");
+        logger.flush();
+
+        assert_eq!(vec![Some("Bearer token".into())], client.authorizations());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uploads_full_batches_without_flush() {
+        let client = RecordingClient::default();
+        let logger = new_log_pipeline("key".into())
+            .with_client(client.clone())
+            .with_max_export_batch_size(2)
+            .with_scheduled_delay(Duration::from_secs(3600))
+            .build_batch(opentelemetry::runtime::Tokio);
+        log(&logger, log::Level::Info, "app", "first");
+        log(&logger, log::Level::Info, "app", "second");
+
+        for _ in 0..100 {
+            if !client.envelopes().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(2, client.envelopes().len());
+    }
+}
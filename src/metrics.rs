@@ -0,0 +1,653 @@
+use crate::{
+    auth::{CachedCredential, TokenCredential},
+    connection_string::{ConnectionString, ConnectionStringError},
+    convert::time_to_string,
+    models::{Data, DataPoint, DataPointType, Envelope, MetricData, Properties},
+    tags::get_tags_for_resource,
+    uploader::{RetryPolicy, Uploader},
+    HttpClient, DEFAULT_ENDPOINT,
+};
+use opentelemetry::{
+    global,
+    metrics::{Descriptor, MetricsError, Result as MetricsResult},
+    runtime::Runtime,
+    sdk::{
+        export::metrics::{
+            CheckpointSet, Count, ExportKind, ExportKindFor, ExportKindSelector, Exporter,
+            LastValue, Max, Min, Points, Record, Sum,
+        },
+        metrics::{
+            aggregators::{
+                ArrayAggregator, HistogramAggregator, LastValueAggregator,
+                MinMaxSumCountAggregator, SumAggregator,
+            },
+            controllers::{self, PushController},
+            selectors::simple,
+        },
+        Resource,
+    },
+};
+use std::{convert::TryInto, error::Error as StdError, fmt, sync::Arc, time::Duration};
+
+/// Create a new Application Insights metrics exporter pipeline builder
+pub fn new_metrics_pipeline(instrumentation_key: String) -> MetricsPipelineBuilder<()> {
+    MetricsPipelineBuilder {
+        client: (),
+        endpoint: None,
+        instrumentation_key,
+        resource: None,
+        period: None,
+        retry_policy: RetryPolicy::default(),
+        credential: None,
+        #[cfg(feature = "compression")]
+        compression: None,
+    }
+}
+
+/// Create a new Application Insights metrics exporter pipeline builder from a [connection
+/// string]. The connection string determines the instrumentation key and the ingestion endpoint.
+///
+/// [connection string]: https://docs.microsoft.com/en-us/azure/azure-monitor/app/sdk-connection-string
+pub fn new_metrics_pipeline_from_connection_string(
+    connection_string: &str,
+) -> Result<MetricsPipelineBuilder<()>, ConnectionStringError> {
+    let connection_string: ConnectionString = connection_string.parse()?;
+    let mut builder = new_metrics_pipeline(connection_string.instrumentation_key().to_string());
+    builder.endpoint = Some(connection_string.track_endpoint());
+    Ok(builder)
+}
+
+/// Application Insights metrics exporter pipeline builder
+#[derive(Debug)]
+pub struct MetricsPipelineBuilder<C> {
+    client: C,
+    endpoint: Option<http::Uri>,
+    instrumentation_key: String,
+    resource: Option<Resource>,
+    period: Option<Duration>,
+    retry_policy: RetryPolicy,
+    credential: Option<Box<dyn TokenCredential>>,
+    #[cfg(feature = "compression")]
+    compression: Option<flate2::Compression>,
+}
+
+impl<C> MetricsPipelineBuilder<C> {
+    /// Set HTTP client, which the exporter will use to send metrics to Application Insights.
+    ///
+    /// Use this to set an HTTP client which fits your async runtime.
+    pub fn with_client<NC>(self, client: NC) -> MetricsPipelineBuilder<NC> {
+        MetricsPipelineBuilder {
+            client,
+            endpoint: self.endpoint,
+            instrumentation_key: self.instrumentation_key,
+            resource: self.resource,
+            period: self.period,
+            retry_policy: self.retry_policy,
+            credential: self.credential,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+        }
+    }
+
+    /// Set endpoint used to ingest telemetry. This should consist of scheme and authrity. The
+    /// exporter will call `/v2/track` on the specified endpoint.
+    ///
+    /// Default: https://dc.services.visualstudio.com
+    pub fn with_endpoint(
+        mut self,
+        endpoint: &str,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync + 'static>> {
+        self.endpoint = Some(format!("{}/v2/track", endpoint).try_into()?);
+        Ok(self)
+    }
+
+    /// Set the resource, which is mapped to context tags (e.g. `service.name` to cloud role) the
+    /// same way as for spans.
+    ///
+    /// Default: no resource
+    pub fn with_resource(self, resource: Resource) -> Self {
+        MetricsPipelineBuilder {
+            resource: Some(resource),
+            ..self
+        }
+    }
+
+    /// Set the interval in which metrics are exported.
+    ///
+    /// Default: 10 seconds
+    pub fn with_period(self, period: Duration) -> Self {
+        MetricsPipelineBuilder {
+            period: Some(period),
+            ..self
+        }
+    }
+
+    /// Set the maximum number of attempts for uploading metrics, including the first one. A value
+    /// of 1 disables retries.
+    ///
+    /// Default: 5
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.retry_policy.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the total time budget for uploading metrics, including all retries.
+    ///
+    /// Default: 20 seconds
+    pub fn with_retry_timeout(mut self, timeout: Duration) -> Self {
+        self.retry_policy.timeout = timeout;
+        self
+    }
+
+    /// Authenticate uploads with Azure Active Directory access tokens from the given credential.
+    /// This is required if local authentication is disabled for the Application Insights
+    /// resource.
+    ///
+    /// Default: no authentication
+    pub fn with_token_credential<T: TokenCredential + 'static>(mut self, credential: T) -> Self {
+        self.credential = Some(Box::new(credential));
+        self
+    }
+
+    /// Compress uploads with gzip.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: disabled
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = if enabled {
+            Some(self.compression.unwrap_or_default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Set the gzip compression level and enable compression. See
+    /// `PipelineBuilder::with_compression_level`.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: 6
+    #[cfg(feature = "compression")]
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.compression = Some(flate2::Compression::new(level.min(9)));
+        self
+    }
+}
+
+impl<C> MetricsPipelineBuilder<C>
+where
+    C: HttpClient + 'static,
+{
+    /// Build a push controller, which exports metrics periodically. The worker of the controller
+    /// runs on the given runtime and uploads are spawned on it, so exports don't wait for the
+    /// upload.
+    ///
+    /// Note: This example requires [`reqwest`] and the **reqwest-client** and
+    /// **opentelemetry/rt-tokio** features.
+    ///
+    /// [`reqwest`]: https://crates.io/crates/reqwest
+    ///
+    /// ```no_run
+    /// use opentelemetry::{metrics::MeterProvider as _, KeyValue};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let instrumentation_key = std::env::var("INSTRUMENTATION_KEY").unwrap();
+    ///     let controller = opentelemetry_application_insights::new_metrics_pipeline(instrumentation_key)
+    ///         .with_client(reqwest::Client::new())
+    ///         .build(opentelemetry::runtime::Tokio);
+    ///
+    ///     let meter = controller.provider().meter("example", None);
+    ///     let requests = meter.u64_counter("requests").init();
+    ///     requests.add(1, &[KeyValue::new("route", "/")]);
+    /// }
+    /// ```
+    pub fn build<R: Runtime>(self, runtime: R) -> PushController {
+        let mut uploader = default_uploader();
+        if let Some(endpoint) = self.endpoint {
+            uploader.endpoint = endpoint;
+        }
+        uploader.retry_policy = self.retry_policy;
+        uploader.credential = self.credential.map(CachedCredential::new);
+        #[cfg(feature = "compression")]
+        {
+            uploader.compression = self.compression;
+        }
+        let exporter = MetricsExporter::with_uploader(
+            self.instrumentation_key,
+            self.client,
+            uploader,
+            runtime.clone(),
+        );
+
+        let spawn_runtime = runtime.clone();
+        let mut builder = controllers::push(
+            simple::Selector::Exact,
+            ExportKindSelector::Stateless,
+            exporter,
+            move |worker| spawn_runtime.spawn(Box::pin(worker)),
+            move |period| runtime.interval(period),
+        );
+        if let Some(resource) = self.resource {
+            builder = builder.with_resource(resource);
+        }
+        if let Some(period) = self.period {
+            builder = builder.with_period(period);
+        }
+
+        builder.build()
+    }
+
+    /// Install an Application Insights metrics pipeline.
+    ///
+    /// This registers the controller's `MeterProvider` globally. See the `build` function if you
+    /// don't need that.
+    pub fn install<R: Runtime>(self, runtime: R) -> PushController {
+        let controller = self.build(runtime);
+        global::set_meter_provider(controller.provider());
+        controller
+    }
+}
+
+fn default_uploader() -> Uploader {
+    Uploader::new(
+        DEFAULT_ENDPOINT
+            .try_into()
+            .expect("hardcoded endpoint is valid uri"),
+    )
+}
+
+/// Application Insights metrics exporter
+///
+/// Counters and up-down counters are exported as the sum of the values recorded since the last
+/// export. Sum observers report their current value. Value recorders are exported as aggregations
+/// with count, min, max and standard deviation.
+///
+/// Exports create the telemetry synchronously and spawn the upload on the runtime. Upload errors
+/// are reported to the global error handler.
+pub struct MetricsExporter<C, R> {
+    client: Arc<C>,
+    instrumentation_key: String,
+    uploader: Arc<Uploader>,
+    runtime: R,
+}
+
+impl<C: fmt::Debug, R> fmt::Debug for MetricsExporter<C, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsExporter")
+            .field("client", &self.client)
+            .field("instrumentation_key", &self.instrumentation_key)
+            .field("uploader", &self.uploader)
+            .finish()
+    }
+}
+
+impl<C, R> MetricsExporter<C, R> {
+    /// Create a new metrics exporter, which uploads metrics from tasks spawned on the runtime.
+    pub fn new(instrumentation_key: String, client: C, runtime: R) -> Self {
+        Self::with_uploader(instrumentation_key, client, default_uploader(), runtime)
+    }
+
+    fn with_uploader(
+        instrumentation_key: String,
+        client: C,
+        uploader: Uploader,
+        runtime: R,
+    ) -> Self {
+        Self {
+            client: Arc::new(client),
+            instrumentation_key,
+            uploader: Arc::new(uploader),
+            runtime,
+        }
+    }
+
+    /// The uploader is shared with upload tasks, which are spawned by exports. Changing it after
+    /// an export leaves the uploads in progress alone and affects later exports only.
+    fn uploader_mut(&mut self) -> &mut Uploader {
+        Arc::make_mut(&mut self.uploader)
+    }
+
+    /// Set endpoint used to ingest telemetry. This should consist of scheme and authrity. The
+    /// exporter will call `/v2/track` on the specified endpoint.
+    ///
+    /// Default: https://dc.services.visualstudio.com
+    pub fn with_endpoint(
+        mut self,
+        endpoint: &str,
+    ) -> Result<Self, Box<dyn StdError + Send + Sync + 'static>> {
+        self.uploader_mut().endpoint = format!("{}/v2/track", endpoint).try_into()?;
+        Ok(self)
+    }
+
+    /// Set the maximum number of attempts for uploading metrics, including the first one. A value
+    /// of 1 disables retries.
+    ///
+    /// Default: 5
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.uploader_mut().retry_policy.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the total time budget for uploading metrics, including all retries.
+    ///
+    /// Default: 20 seconds
+    pub fn with_retry_timeout(mut self, timeout: Duration) -> Self {
+        self.uploader_mut().retry_policy.timeout = timeout;
+        self
+    }
+
+    /// Authenticate uploads with Azure Active Directory access tokens from the given credential.
+    /// Tokens are cached and refreshed shortly before they expire.
+    ///
+    /// Default: no authentication
+    pub fn with_token_credential<T: TokenCredential + 'static>(mut self, credential: T) -> Self {
+        self.uploader_mut().credential = Some(CachedCredential::new(Box::new(credential)));
+        self
+    }
+
+    /// Compress uploads with gzip.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: disabled
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.uploader_mut().compression = if enabled {
+            Some(self.uploader_mut().compression.unwrap_or_default())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Set the gzip compression level and enable compression. See
+    /// `PipelineBuilder::with_compression_level`.
+    ///
+    /// Requires the **compression** feature.
+    ///
+    /// Default: 6
+    #[cfg(feature = "compression")]
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.uploader_mut().compression = Some(flate2::Compression::new(level.min(9)));
+        self
+    }
+
+    fn create_envelope(&self, record: &Record<'_>) -> MetricsResult<Option<Envelope>> {
+        let point = match data_point(record)? {
+            Some(point) => point,
+            None => return Ok(None),
+        };
+        let properties: Properties = record
+            .labels()
+            .iter()
+            .map(|(k, v)| (k.as_str().into(), v.into()))
+            .collect();
+
+        Ok(Some(Envelope {
+            name: "Microsoft.ApplicationInsights.Metric".into(),
+            time: time_to_string(*record.end_time()).into(),
+            sample_rate: None,
+            i_key: Some(self.instrumentation_key.clone().into()),
+            tags: Some(get_tags_for_resource(record.resource())),
+            data: Some(Data::Metric(MetricData {
+                ver: 2,
+                metrics: vec![point],
+                properties: Some(properties).filter(|x| !x.is_empty()),
+            })),
+        }))
+    }
+}
+
+impl<C, R> Exporter for MetricsExporter<C, R>
+where
+    C: HttpClient + 'static,
+    R: Runtime,
+{
+    fn export(&self, checkpoint_set: &mut dyn CheckpointSet) -> MetricsResult<()> {
+        let mut envelopes = Vec::new();
+        checkpoint_set.try_for_each(self, &mut |record| {
+            envelopes.extend(self.create_envelope(record)?);
+            Ok(())
+        })?;
+
+        if !envelopes.is_empty() {
+            // The metrics SDK exports synchronously, so upload in the background instead of
+            // blocking the worker.
+            let client = Arc::clone(&self.client);
+            let uploader = Arc::clone(&self.uploader);
+            self.runtime.spawn(Box::pin(async move {
+                if let Err(err) = uploader.send(&*client, envelopes).await {
+                    global::handle_error(MetricsError::from(err));
+                }
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+impl<C, R> ExportKindFor for MetricsExporter<C, R>
+where
+    C: HttpClient,
+{
+    fn export_kind_for(&self, descriptor: &Descriptor) -> ExportKind {
+        ExportKindSelector::Stateless.export_kind_for(descriptor)
+    }
+}
+
+/// Converts the aggregation of a record into a data point. Returns `None` for aggregators, which
+/// cannot be represented in Application Insights.
+fn data_point(record: &Record<'_>) -> MetricsResult<Option<DataPoint>> {
+    let aggregator = record.aggregator().ok_or(MetricsError::NoDataCollected)?;
+    let descriptor = record.descriptor();
+    let kind = descriptor.number_kind();
+    let mut point = DataPoint {
+        ns: Some(descriptor.instrumentation_name())
+            .filter(|ns| !ns.is_empty())
+            .map(Into::into),
+        name: descriptor.name().into(),
+        kind: DataPointType::Measurement,
+        value: 0.0,
+        count: None,
+        min: None,
+        max: None,
+        std_dev: None,
+    };
+
+    let aggregator = aggregator.as_any();
+    if let Some(sum) = aggregator.downcast_ref::<SumAggregator>() {
+        point.value = sum.sum()?.to_f64(kind);
+    } else if let Some(last_value) = aggregator.downcast_ref::<LastValueAggregator>() {
+        point.value = last_value.last_value()?.0.to_f64(kind);
+    } else if let Some(array) = aggregator.downcast_ref::<ArrayAggregator>() {
+        let values: Vec<f64> = array.points()?.iter().map(|p| p.to_f64(kind)).collect();
+        if values.is_empty() {
+            return Ok(None);
+        }
+
+        let count = values.len() as f64;
+        let sum: f64 = values.iter().sum();
+        let mean = sum / count;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / count;
+        point.kind = DataPointType::Aggregation;
+        point.value = sum;
+        point.count = Some(to_count(values.len() as u64));
+        point.min = values.iter().copied().reduce(f64::min);
+        point.max = values.iter().copied().reduce(f64::max);
+        point.std_dev = Some(variance.sqrt());
+    } else if let Some(mmsc) = aggregator.downcast_ref::<MinMaxSumCountAggregator>() {
+        point.kind = DataPointType::Aggregation;
+        point.value = mmsc.sum()?.to_f64(kind);
+        point.count = Some(to_count(mmsc.count()?));
+        point.min = Some(mmsc.min()?.to_f64(kind));
+        point.max = Some(mmsc.max()?.to_f64(kind));
+    } else if let Some(histogram) = aggregator.downcast_ref::<HistogramAggregator>() {
+        point.kind = DataPointType::Aggregation;
+        point.value = histogram.sum()?.to_f64(kind);
+        point.count = Some(to_count(histogram.count()?));
+    } else {
+        return Ok(None);
+    }
+
+    Ok(Some(point))
+}
+
+fn to_count(count: u64) -> i32 {
+    count.try_into().unwrap_or(i32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::tests::RecordingClient;
+    use futures_util::{
+        future::{self, BoxFuture},
+        stream,
+    };
+    use opentelemetry::{metrics::MeterProvider as _, KeyValue};
+    use serde_json::Value;
+    use std::{
+        mem,
+        sync::{Arc, Mutex},
+    };
+
+    /// Runtime, which collects spawned tasks to run them on demand.
+    #[derive(Clone, Default)]
+    struct TaskQueue {
+        tasks: Arc<Mutex<Vec<BoxFuture<'static, ()>>>>,
+    }
+
+    impl TaskQueue {
+        /// Runs all tasks including the ones they spawn.
+        fn run(&self) {
+            loop {
+                let tasks = mem::take(&mut *self.tasks.lock().unwrap());
+                if tasks.is_empty() {
+                    break;
+                }
+                for task in tasks {
+                    futures_executor::block_on(task);
+                }
+            }
+        }
+    }
+
+    impl Runtime for TaskQueue {
+        type Interval = stream::Pending<()>;
+        type Delay = future::Ready<()>;
+
+        fn interval(&self, _duration: Duration) -> Self::Interval {
+            stream::pending()
+        }
+
+        fn spawn(&self, future: BoxFuture<'static, ()>) {
+            self.tasks.lock().unwrap().push(future);
+        }
+
+        fn delay(&self, _duration: Duration) -> Self::Delay {
+            future::ready(())
+        }
+    }
+
+    /// Records metrics and exports them once by shutting down the controller.
+    fn export(record: impl FnOnce(&opentelemetry::metrics::Meter)) -> Vec<Value> {
+        let client = RecordingClient::default();
+        let runtime = TaskQueue::default();
+        let controller = new_metrics_pipeline("key".into())
+            .with_client(client.clone())
+            .with_resource(Resource::new(vec![KeyValue::new("service.name", "svc")]))
+            .build(runtime.clone());
+        record(&controller.provider().meter("test-meter", None));
+        drop(controller);
+        runtime.run();
+
+        let mut envelopes = client.envelopes();
+        envelopes
+            .sort_by_key(|envelope| envelope["data"]["baseData"]["metrics"][0]["name"].to_string());
+        envelopes
+    }
+
+    #[test]
+    fn uploads_in_spawned_task() {
+        let client = RecordingClient::default();
+        let runtime = TaskQueue::default();
+        let controller = new_metrics_pipeline("key".into())
+            .with_client(client.clone())
+            .with_max_attempts(1)
+            .build(runtime.clone());
+        let meter = controller.provider().meter("test-meter", None);
+        meter.u64_counter("requests").init().add(1, &[]);
+        drop(controller);
+
+        // Running the worker exports the metrics, which only spawns the upload.
+        let worker = runtime.tasks.lock().unwrap().remove(0);
+        futures_executor::block_on(worker);
+        assert!(client.envelopes().is_empty());
+        assert_eq!(1, runtime.tasks.lock().unwrap().len());
+
+        runtime.run();
+        assert_eq!(1, client.envelopes().len());
+    }
+
+    #[test]
+    fn changes_uploader_while_uploading() {
+        let exporter = MetricsExporter::new(
+            "key".into(),
+            RecordingClient::default(),
+            TaskQueue::default(),
+        );
+        // An upload task of a previous export.
+        let uploading = Arc::clone(&exporter.uploader);
+        let exporter = exporter.with_max_attempts(1);
+
+        assert_eq!(5, uploading.retry_policy.max_attempts);
+        assert_eq!(1, exporter.uploader.retry_policy.max_attempts);
+    }
+
+    #[test]
+    fn exports_counters() {
+        let envelopes = export(|meter| {
+            let counter = meter.u64_counter("requests").init();
+            counter.add(2, &[KeyValue::new("route", "/")]);
+            counter.add(3, &[KeyValue::new("route", "/")]);
+            meter.i64_up_down_counter("queue").init().add(-4, &[]);
+        });
+
+        assert_eq!(2, envelopes.len());
+        let queue = &envelopes[0];
+        assert_eq!("Microsoft.ApplicationInsights.Metric", queue["name"]);
+        assert_eq!("svc", queue["tags"]["ai.cloud.role"]);
+        assert_eq!("MetricData", queue["data"]["baseType"]);
+        let point = &queue["data"]["baseData"]["metrics"][0];
+        assert_eq!("test-meter", point["ns"]);
+        assert_eq!("Measurement", point["kind"]);
+        assert_eq!(-4.0, point["value"]);
+
+        let requests = &envelopes[1]["data"]["baseData"];
+        assert_eq!(5.0, requests["metrics"][0]["value"]);
+        assert_eq!("/", requests["properties"]["route"]);
+    }
+
+    #[test]
+    fn exports_value_recorders_as_aggregations() {
+        let envelopes = export(|meter| {
+            let recorder = meter.f64_value_recorder("duration").init();
+            for value in &[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+                recorder.record(*value, &[]);
+            }
+        });
+
+        assert_eq!(1, envelopes.len());
+        let point = &envelopes[0]["data"]["baseData"]["metrics"][0];
+        assert_eq!("Aggregation", point["kind"]);
+        assert_eq!(40.0, point["value"]);
+        assert_eq!(8, point["count"]);
+        assert_eq!(2.0, point["min"]);
+        assert_eq!(9.0, point["max"]);
+        assert_eq!(2.0, point["stdDev"]);
+    }
+}
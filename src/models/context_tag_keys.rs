@@ -14,7 +14,7 @@ impl ContextTagKey {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub(crate) struct Tags(BTreeMap<&'static str, String>);
 
 impl Tags {
@@ -31,6 +31,10 @@ impl Tags {
     pub(crate) fn get(&self, key: &ContextTagKey) -> Option<&String> {
         self.0.get(key.key)
     }
+
+    pub(crate) fn extend(&mut self, other: Tags) {
+        self.0.extend(other.0)
+    }
 }
 
 macro_rules! context_tag_keys {
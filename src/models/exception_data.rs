@@ -1,4 +1,4 @@
-use crate::models::{ExceptionDetails, Properties};
+use crate::models::{ExceptionDetails, Measurements, Properties};
 use serde::Serialize;
 
 /// An instance of Exception represents a handled or unhandled exception that occurred during
@@ -15,4 +15,8 @@ pub(crate) struct ExceptionData {
     /// Collection of custom properties.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) properties: Option<Properties>,
+
+    /// Collection of custom measurements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) measurements: Option<Measurements>,
 }
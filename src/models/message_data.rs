@@ -1,9 +1,8 @@
-use crate::models::{LimitedLenString32768, Properties};
+use crate::models::{LimitedLenString32768, Measurements, Properties, SeverityLevel};
 use serde::Serialize;
 
 /// Instances of Message represent printf-like trace statements that are text-searched. Log4Net,
-/// NLog and other text-based log file entries are translated into intances of this type. The
-/// message does not have measurements.
+/// NLog and other text-based log file entries are translated into intances of this type.
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct MessageData {
@@ -13,7 +12,15 @@ pub(crate) struct MessageData {
     /// Trace message
     pub(crate) message: LimitedLenString32768,
 
+    /// Trace severity level.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) severity_level: Option<SeverityLevel>,
+
     /// Collection of custom properties.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) properties: Option<Properties>,
+
+    /// Collection of custom measurements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) measurements: Option<Measurements>,
 }
@@ -1,21 +1,35 @@
+mod availability_data;
 pub(crate) mod context_tag_keys;
 mod data;
+mod data_point;
 mod envelope;
+mod event_data;
 mod exception_data;
 mod exception_details;
 mod message_data;
+mod metric_data;
+mod page_view_data;
 mod remote_dependency_data;
 mod request_data;
 mod sanitize;
+mod severity_level;
+mod stack_frame;
 
+pub(crate) use availability_data::*;
 pub(crate) use data::*;
+pub(crate) use data_point::*;
 pub(crate) use envelope::*;
+pub(crate) use event_data::*;
 pub(crate) use exception_data::*;
 pub(crate) use exception_details::*;
 pub(crate) use message_data::*;
+pub(crate) use metric_data::*;
+pub(crate) use page_view_data::*;
 pub(crate) use remote_dependency_data::*;
 pub(crate) use request_data::*;
 pub(crate) use sanitize::*;
+pub use severity_level::*;
+pub(crate) use stack_frame::*;
 
 #[cfg(test)]
 mod tests {
@@ -33,7 +47,9 @@ mod tests {
             data: Some(Data::Message(MessageData {
                 ver: 2,
                 message: "hello world".into(),
+                severity_level: None,
                 properties: None,
+                measurements: None,
             })),
         };
         let serialized = serde_json::to_string(&envelope).unwrap();
@@ -54,7 +70,9 @@ mod tests {
             data: Some(Data::Message(MessageData {
                 ver: 2,
                 message: "m".repeat(33000).into(),
+                severity_level: None,
                 properties: None,
+                measurements: None,
             })),
         };
         assert_eq!(1024, envelope.name.as_ref().len());
@@ -0,0 +1,39 @@
+use crate::models::{
+    LimitedLenString128, LimitedLenString2048, LimitedLenString512, Measurements, Properties,
+};
+use serde::Serialize;
+
+/// An instance of PageView represents a view of a page, e.g. a screen of an application, with its
+/// name, URL and the time it took to show the page.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PageViewData {
+    /// Schema version
+    pub(crate) ver: i32,
+
+    /// Page name. Keep it low cardinality to allow proper grouping and useful metrics.
+    pub(crate) name: LimitedLenString512,
+
+    /// Request URL with all query string parameters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) url: Option<LimitedLenString2048>,
+
+    /// Request duration in format: DD.HH:MM:SS.MMMMMM. For a page view (PageViewData), this is the
+    /// duration. For a page view with performance information (PageViewPerfData), this is the page
+    /// load time. Must be less than 1000 days.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) duration: Option<String>,
+
+    /// Identifier of a page view instance. Used for correlation between page view and other
+    /// telemetry items.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<LimitedLenString128>,
+
+    /// Collection of custom properties.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) properties: Option<Properties>,
+
+    /// Collection of custom measurements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) measurements: Option<Measurements>,
+}
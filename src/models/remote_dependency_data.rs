@@ -1,4 +1,6 @@
-use crate::models::{LimitedLenString1024, LimitedLenString128, LimitedLenString8192, Properties};
+use crate::models::{
+    LimitedLenString1024, LimitedLenString128, LimitedLenString8192, Measurements, Properties,
+};
 use serde::Serialize;
 
 /// An instance of Remote Dependency represents an interaction of the monitored component with a
@@ -47,4 +49,8 @@ pub(crate) struct RemoteDependencyData {
     /// Collection of custom properties.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) properties: Option<Properties>,
+
+    /// Collection of custom measurements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) measurements: Option<Measurements>,
 }
@@ -1,4 +1,6 @@
-use crate::models::{LimitedLenString1024, LimitedLenString128, LimitedLenString2048, Properties};
+use crate::models::{
+    LimitedLenString1024, LimitedLenString128, LimitedLenString2048, Measurements, Properties,
+};
 use serde::Serialize;
 
 /// An instance of Request represents completion of an external request to the application to do
@@ -40,4 +42,8 @@ pub(crate) struct RequestData {
     /// Collection of custom properties.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) properties: Option<Properties>,
+
+    /// Collection of custom measurements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) measurements: Option<Measurements>,
 }
@@ -18,7 +18,7 @@ macro_rules! limited_len_string {
             }
         }
 
-        impl <'a> From<std::borrow::Cow<'a, str>> for $name {
+        impl<'a> From<std::borrow::Cow<'a, str>> for $name {
             fn from(s: std::borrow::Cow<'a, str>) -> Self {
                 Self(String::from(&s[0..std::cmp::min(s.len(), $len)]))
             }
@@ -43,9 +43,13 @@ limited_len_string!(LimitedLenString32768, 32768);
 limited_len_string!(LimitedLenString8192, 8192);
 limited_len_string!(LimitedLenString2048, 2048);
 limited_len_string!(LimitedLenString1024, 1024);
+limited_len_string!(LimitedLenString512, 512);
+limited_len_string!(LimitedLenString256, 256);
 limited_len_string!(LimitedLenString150, 150);
 limited_len_string!(LimitedLenString128, 128);
 limited_len_string!(LimitedLenString64, 64);
 limited_len_string!(LimitedLenString40, 40);
 
 pub(crate) type Properties = BTreeMap<LimitedLenString150, LimitedLenString8192>;
+
+pub(crate) type Measurements = BTreeMap<LimitedLenString150, f64>;
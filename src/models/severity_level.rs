@@ -0,0 +1,16 @@
+use serde::Serialize;
+
+/// Defines the level of severity for trace telemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SeverityLevel {
+    /// Verbose, e.g. debug and trace logs.
+    Verbose,
+    /// Information
+    Information,
+    /// Warning
+    Warning,
+    /// Error
+    Error,
+    /// Critical, e.g. failures the application can't recover from.
+    Critical,
+}
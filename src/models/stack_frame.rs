@@ -0,0 +1,26 @@
+use crate::models::LimitedLenString1024;
+use serde::Serialize;
+
+/// Stack frame information.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StackFrame {
+    /// Level in the call stack. For the long stacks SDK may not report every function in a call
+    /// stack.
+    pub(crate) level: i32,
+
+    /// Method name.
+    pub(crate) method: LimitedLenString1024,
+
+    /// Name of the assembly (dll, jar, etc.) containing this function.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) assembly: Option<LimitedLenString1024>,
+
+    /// File name or URL of the method implementation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file_name: Option<LimitedLenString1024>,
+
+    /// Line number of the code implementation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) line: Option<i32>,
+}
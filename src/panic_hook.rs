@@ -0,0 +1,206 @@
+use opentelemetry::{
+    sdk::trace::{Tracer, TracerProvider},
+    trace::{Span as _, StatusCode, TraceContextExt as _, Tracer as _, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_semantic_conventions as semcov;
+use std::{backtrace::Backtrace, panic, sync::mpsc, thread, time::Duration};
+
+/// Install a panic hook, which reports panics as exception telemetry.
+///
+/// The exception contains the panic message, the location and a backtrace. It's recorded on a new
+/// span named `panic`, which is a child of the current span and is ended right away, so the
+/// exception is exported even if the process aborts before the current span ends. The current
+/// span is marked as failed, but it's still ended by its owner, e.g. when it's dropped while
+/// unwinding. Afterwards the hook flushes all span processors of the tracer provider, waiting at
+/// most for the given timeout, and calls the previously installed panic hook.
+///
+/// The hook doesn't keep the tracer provider alive, so it still shuts down when it's dropped.
+/// Panics after that are only passed to the previous hook.
+///
+/// Flushing only has an effect for batch span processors. The simple span processor exports spans
+/// on a background thread as soon as they end and the hook doesn't wait for this.
+///
+/// Note: This example requires [`reqwest`] and the **reqwest-client** and
+/// **opentelemetry/rt-tokio** features.
+///
+/// [`reqwest`]: https://crates.io/crates/reqwest
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let tracer_provider = opentelemetry_application_insights::new_pipeline("...".into())
+///         .with_client(reqwest::Client::new())
+///         .build_batch(opentelemetry::runtime::Tokio);
+///     opentelemetry_application_insights::install_panic_hook(
+///         &tracer_provider,
+///         Duration::from_secs(5),
+///     );
+/// }
+/// ```
+pub fn install_panic_hook(tracer_provider: &TracerProvider, timeout: Duration) {
+    // The tracer only holds a weak reference to the tracer provider.
+    let tracer = tracer_provider.get_tracer(
+        "opentelemetry-application-insights",
+        Some(env!("CARGO_PKG_VERSION")),
+    );
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = if let Some(message) = info.payload().downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = info.payload().downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Box<dyn Any>".into()
+        };
+        let location = info
+            .location()
+            .map(|location| (location.file(), location.line()));
+        if let Some(tracer_provider) = tracer.provider() {
+            record_panic(&tracer, message, location);
+            flush(tracer_provider, timeout);
+        }
+
+        previous_hook(info);
+    }));
+}
+
+fn record_panic(tracer: &Tracer, message: String, location: Option<(&str, u32)>) {
+    let mut attributes = vec![
+        KeyValue::new(semcov::trace::EXCEPTION_TYPE, "panic"),
+        KeyValue::new(semcov::trace::EXCEPTION_MESSAGE, message.clone()),
+        KeyValue::new(
+            semcov::trace::EXCEPTION_STACKTRACE,
+            Backtrace::force_capture().to_string(),
+        ),
+    ];
+    if let Some((file, line)) = location {
+        attributes.push(KeyValue::new(
+            semcov::trace::CODE_FILEPATH,
+            file.to_string(),
+        ));
+        attributes.push(KeyValue::new(semcov::trace::CODE_LINENO, line as i64));
+    }
+
+    let cx = Context::current();
+    let mut span = tracer.start_with_context("panic", cx.clone());
+    span.add_event("exception".into(), attributes);
+    span.set_status(StatusCode::Error, message.clone());
+    span.end();
+
+    let current_span = cx.span();
+    if current_span.is_recording() {
+        current_span.set_status(StatusCode::Error, message);
+    }
+}
+
+/// Flushes all span processors on a separate thread, because flushing may block forever, e.g. if
+/// the panic happened on the thread the batch span processor runs on.
+fn flush(tracer_provider: TracerProvider, timeout: Duration) {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for processor in tracer_provider.span_processors() {
+            let _ = processor.force_flush();
+        }
+        let _ = sender.send(());
+    });
+    let _ = receiver.recv_timeout(timeout);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_client::tests::RecordingClient, tests::tracer_provider, Exporter};
+    use opentelemetry::trace::SpanKind;
+
+    #[test]
+    fn records_panic_on_current_span() {
+        let client = RecordingClient::default();
+        let tracer_provider = tracer_provider(Exporter::new("key".into(), client.clone()));
+        let tracer = tracer_provider.get_tracer("test", None);
+        let span = tracer
+            .span_builder("request")
+            .with_kind(SpanKind::Server)
+            .start(&tracer);
+        {
+            let _guard = Context::current_with_span(span).attach();
+            record_panic(&tracer, "boom".into(), Some(("src/main.rs", 7)));
+            // The owner ends the span, e.g. when it's dropped while unwinding.
+            assert!(Context::current().span().is_recording());
+        }
+        // Shutting down the provider waits for the simple span processor to export all spans.
+        drop(tracer_provider);
+
+        let envelopes = client.envelopes();
+        assert_eq!(3, envelopes.len());
+        assert_eq!("panic", envelopes[0]["data"]["baseData"]["name"]);
+        assert_eq!(false, envelopes[0]["data"]["baseData"]["success"]);
+        assert_eq!(
+            "Microsoft.ApplicationInsights.Exception",
+            envelopes[1]["name"]
+        );
+        assert_eq!(
+            "Microsoft.ApplicationInsights.Request",
+            envelopes[2]["name"]
+        );
+        assert_eq!(false, envelopes[2]["data"]["baseData"]["success"]);
+        assert_eq!(
+            envelopes[2]["data"]["baseData"]["id"],
+            envelopes[0]["tags"]["ai.operation.parentId"]
+        );
+        assert_eq!(
+            envelopes[2]["tags"]["ai.operation.id"],
+            envelopes[1]["tags"]["ai.operation.id"]
+        );
+        let data = &envelopes[1]["data"]["baseData"];
+        assert_eq!("panic", data["exceptions"][0]["typeName"]);
+        assert_eq!("boom", data["exceptions"][0]["message"]);
+        assert!(data["exceptions"][0]["parsedStack"].is_array());
+        assert_eq!("src/main.rs", data["properties"]["code.filepath"]);
+        assert_eq!("7", data["properties"]["code.lineno"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flush_exports_panic_while_current_span_is_open() {
+        let client = RecordingClient::default();
+        let tracer_provider = TracerProvider::builder()
+            .with_batch_exporter(
+                Exporter::new("key".into(), client.clone()),
+                opentelemetry::runtime::Tokio,
+            )
+            .build();
+        let tracer = tracer_provider.get_tracer("test", None);
+        let span = tracer.start("request");
+        let _guard = Context::current_with_span(span).attach();
+        record_panic(&tracer, "boom".into(), None);
+        flush(tracer_provider.clone(), Duration::from_secs(5));
+
+        let envelopes = client.envelopes();
+        assert_eq!(2, envelopes.len());
+        assert_eq!("panic", envelopes[0]["data"]["baseData"]["name"]);
+        assert_eq!(
+            "boom",
+            envelopes[1]["data"]["baseData"]["exceptions"][0]["message"]
+        );
+    }
+
+    #[test]
+    fn records_panic_on_new_span() {
+        let client = RecordingClient::default();
+        let tracer_provider = tracer_provider(Exporter::new("key".into(), client.clone()));
+        let tracer = tracer_provider.get_tracer("test", None);
+        record_panic(&tracer, "boom".into(), None);
+        drop(tracer_provider);
+
+        let envelopes = client.envelopes();
+        assert_eq!(2, envelopes.len());
+        assert_eq!("panic", envelopes[0]["data"]["baseData"]["name"]);
+        assert_eq!(false, envelopes[0]["data"]["baseData"]["success"]);
+        assert_eq!(
+            "boom",
+            envelopes[1]["data"]["baseData"]["exceptions"][0]["message"]
+        );
+    }
+}
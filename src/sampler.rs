@@ -0,0 +1,545 @@
+use crate::convert::trace_id_to_string;
+use opentelemetry::{
+    sdk::{
+        export::trace::SpanData,
+        trace::{SamplingDecision, SamplingResult, ShouldSample},
+    },
+    trace::{Link, SpanKind, TraceContextExt as _, TraceId, TraceState},
+    Context, Key, KeyValue, Value,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Attribute containing the sample rate (a value between 0 and 1), with which a span was sampled.
+/// The exporter sends it as the sample rate of the span's telemetry instead of the configured one.
+/// The samplers of this crate record it on sampled spans and custom samplers can do the same.
+pub const SAMPLE_RATE: Key = Key::from_static_str("ai.sample_rate");
+
+/// Minimum time between two decreases of the sample rate of an `AdaptiveSampler`.
+const DECREASE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// Minimum time between two increases of the sample rate of an `AdaptiveSampler`.
+const INCREASE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Weight of the last evaluation interval in the moving average of sampled items per second.
+const MOVING_AVERAGE_RATIO: f64 = 0.25;
+
+/// Sampler, which makes the same sampling decisions as the Application Insights SDKs.
+///
+/// The decision is based on a hash of the operation id (the trace id), so all spans of a trace
+/// are either sampled or dropped, also across services instrumented with other Application
+/// Insights SDKs using the same sample rate.
+///
+/// Sampled spans get the sample rate as `SAMPLE_RATE` attribute. Use
+/// `PipelineBuilder::with_application_insights_sampler` to install it, which also sets the sample
+/// rate sent to Application Insights for other telemetry.
+#[derive(Debug, Clone)]
+pub struct ApplicationInsightsSampler {
+    percentage: f64,
+}
+
+impl ApplicationInsightsSampler {
+    /// Create a new sampler, which samples the given ratio of traces. It should be a value between
+    /// 0 and 1.
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            percentage: sample_rate * 100.0,
+        }
+    }
+}
+
+impl ShouldSample for ApplicationInsightsSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        _name: &str,
+        _span_kind: &SpanKind,
+        _attributes: &[KeyValue],
+        _links: &[Link],
+    ) -> SamplingResult {
+        let (decision, attributes) = if is_sampled_in(trace_id, self.percentage) {
+            (
+                SamplingDecision::RecordAndSample,
+                vec![SAMPLE_RATE.f64(self.percentage / 100.0)],
+            )
+        } else {
+            (SamplingDecision::Drop, Vec::new())
+        };
+
+        SamplingResult {
+            decision,
+            attributes,
+            trace_state: parent_trace_state(parent_context),
+        }
+    }
+}
+
+/// Sampler, which adjusts the sample rate to keep the number of exported telemetry items per
+/// second below a target, like adaptive sampling of the Application Insights SDKs.
+///
+/// The exporter counts the telemetry items it exports. The sampler measures them per second as a
+/// moving average over evaluation intervals and suggests the sample rate, which would meet the
+/// target. It decreases the sample rate at most every 2 minutes and increases it at most every 15
+/// minutes. Sample rates are rounded to 1/n, so every sampled span represents a whole number of
+/// spans.
+///
+/// Spans with a valid parent follow the sampling decision of the parent. Other decisions are
+/// based on the operation id like with `ApplicationInsightsSampler`. Sampled root spans get the
+/// current sample rate as `SAMPLE_RATE` attribute and as sampling threshold (`th`) in the
+/// OpenTelemetry entry of the trace state, which is passed on to child spans. The exporter sends
+/// it to Application Insights to extrapolate counts.
+///
+/// Clones share the sample rate and the measured items, so install the sampler with
+/// `PipelineBuilder::with_adaptive_sampler`, which also passes it to the exporter.
+///
+/// Note: This example requires [`reqwest`] and the **reqwest-client-blocking** feature.
+///
+/// [`reqwest`]: https://crates.io/crates/reqwest
+///
+/// ```no_run
+/// use opentelemetry_application_insights::AdaptiveSampler;
+///
+/// let tracer = opentelemetry_application_insights::new_pipeline("...".into())
+///     .with_client(reqwest::blocking::Client::new())
+///     .with_adaptive_sampler(AdaptiveSampler::new(5.0).with_sample_rate_bounds(0.01, 1.0))
+///     .install_simple();
+/// ```
+#[derive(Debug, Clone)]
+pub struct AdaptiveSampler {
+    max_items_per_second: f64,
+    min_percentage: f64,
+    max_percentage: f64,
+    evaluation_interval: Duration,
+    state: Arc<Mutex<AdaptiveState>>,
+}
+
+#[derive(Debug)]
+struct AdaptiveState {
+    percentage: f64,
+    items: u64,
+    interval_start: Instant,
+    moving_average: Option<f64>,
+    last_change: Instant,
+}
+
+impl AdaptiveSampler {
+    /// Create a new sampler, which targets the given maximum number of exported telemetry items per
+    /// second.
+    pub fn new(max_items_per_second: f64) -> Self {
+        let now = Instant::now();
+        Self {
+            max_items_per_second,
+            min_percentage: 0.1,
+            max_percentage: 100.0,
+            evaluation_interval: Duration::from_secs(15),
+            state: Arc::new(Mutex::new(AdaptiveState {
+                percentage: 100.0,
+                items: 0,
+                interval_start: now,
+                moving_average: None,
+                last_change: now,
+            })),
+        }
+    }
+
+    /// Set the minimum and maximum sample rate. They should be values between 0 and 1. The
+    /// current sample rate is limited to the new bounds.
+    ///
+    /// Default: 0.001 and 1.0
+    pub fn with_sample_rate_bounds(mut self, min_sample_rate: f64, max_sample_rate: f64) -> Self {
+        self.min_percentage = min_sample_rate * 100.0;
+        self.max_percentage = max_sample_rate * 100.0;
+        if let Ok(mut state) = self.state.lock() {
+            state.percentage = self.clamp(state.percentage);
+        }
+        self
+    }
+
+    /// Set the sample rate used until the first adjustment. It should be a value between 0 and 1
+    /// and is limited to the sample rate bounds.
+    ///
+    /// Default: 1.0
+    pub fn with_initial_sample_rate(self, sample_rate: f64) -> Self {
+        if let Ok(mut state) = self.state.lock() {
+            state.percentage = self.clamp(sample_rate * 100.0);
+        }
+        self
+    }
+
+    /// Set the interval in which the exported telemetry items per second are measured.
+    ///
+    /// Default: 15 seconds
+    pub fn with_evaluation_interval(mut self, evaluation_interval: Duration) -> Self {
+        self.evaluation_interval = evaluation_interval;
+        self
+    }
+
+    /// Current sample rate as percentage.
+    fn percentage(&self) -> f64 {
+        self.state.lock().map_or(100.0, |state| state.percentage)
+    }
+
+    fn clamp(&self, percentage: f64) -> f64 {
+        percentage.max(self.min_percentage).min(self.max_percentage)
+    }
+
+    /// Counts exported telemetry items and adjusts the sample rate at the end of an evaluation
+    /// interval.
+    pub(crate) fn record_items(&self, items: u64, now: Instant) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.items += items;
+
+        let elapsed = now.saturating_duration_since(state.interval_start);
+        if elapsed < self.evaluation_interval {
+            return;
+        }
+        let items_per_second = state.items as f64 / elapsed.as_secs_f64();
+        let moving_average = state.moving_average.map_or(items_per_second, |average| {
+            average * (1.0 - MOVING_AVERAGE_RATIO) + items_per_second * MOVING_AVERAGE_RATIO
+        });
+        state.items = 0;
+        state.interval_start = now;
+        state.moving_average = Some(moving_average);
+
+        let suggested = if moving_average > 0.0 {
+            state.percentage * self.max_items_per_second / moving_average
+        } else {
+            self.max_percentage
+        };
+        // Round to 100/n, so every sampled span represents a whole number of spans.
+        let suggested = self.clamp(100.0 / (100.0 / suggested).ceil());
+
+        let since_last_change = now.saturating_duration_since(state.last_change);
+        if (suggested < state.percentage && since_last_change >= DECREASE_TIMEOUT)
+            || (suggested > state.percentage && since_last_change >= INCREASE_TIMEOUT)
+        {
+            state.percentage = suggested;
+            state.last_change = now;
+            state.moving_average = None;
+        }
+    }
+}
+
+impl ShouldSample for AdaptiveSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        _name: &str,
+        _span_kind: &SpanKind,
+        _attributes: &[KeyValue],
+        _links: &[Link],
+    ) -> SamplingResult {
+        let trace_state = parent_trace_state(parent_context);
+        if let Some(parent) = parent_context
+            .map(|cx| cx.span().span_context().clone())
+            .filter(|parent| parent.is_valid())
+        {
+            return SamplingResult {
+                decision: if parent.is_sampled() {
+                    SamplingDecision::RecordAndSample
+                } else {
+                    SamplingDecision::Drop
+                },
+                attributes: Vec::new(),
+                trace_state,
+            };
+        }
+
+        let percentage = self.percentage();
+        if is_sampled_in(trace_id, percentage) {
+            SamplingResult {
+                decision: SamplingDecision::RecordAndSample,
+                attributes: vec![SAMPLE_RATE.f64(percentage / 100.0)],
+                trace_state: with_sampling_threshold(&trace_state, percentage / 100.0),
+            }
+        } else {
+            SamplingResult {
+                decision: SamplingDecision::Drop,
+                attributes: Vec::new(),
+                trace_state,
+            }
+        }
+    }
+}
+
+/// Sample rate of the span as value between 0 and 1. It's taken from the `SAMPLE_RATE` attribute
+/// or, e.g. for spans sampled by a parent based sampler, from the sampling threshold (`th`) or
+/// p-value (`p`) of the OpenTelemetry entry in the W3C trace state.
+pub(crate) fn span_sample_rate(span: &SpanData) -> Option<f64> {
+    let sample_rate = match span.attributes.get(&SAMPLE_RATE) {
+        Some(Value::F64(sample_rate)) => Some(*sample_rate),
+        Some(Value::I64(sample_rate)) => Some(*sample_rate as f64),
+        _ => span
+            .span_context
+            .trace_state()
+            .get("ot")
+            .and_then(parse_ot_trace_state),
+    };
+    sample_rate.filter(|x| *x > 0.0 && *x <= 1.0)
+}
+
+/// Parses the sampling probability from the value of the `ot` trace state entry, e.g. `th:c` or
+/// `p:2;r:10`.
+fn parse_ot_trace_state(value: &str) -> Option<f64> {
+    value.split(';').find_map(|field| {
+        let (key, value) = field.split_once(':')?;
+        match key {
+            // Rejection threshold with up to 14 hex digits, trailing zeros may be omitted.
+            "th" if !value.is_empty() && value.len() <= 14 => {
+                let threshold = u64::from_str_radix(&format!("{:0<14}", value), 16).ok()?;
+                Some(1.0 - threshold as f64 / (1_u64 << 56) as f64)
+            }
+            // Power of two sampling probability, 63 means a probability of zero.
+            "p" => match value.parse::<i32>() {
+                Ok(p) if (0..63).contains(&p) => Some(2_f64.powi(-p)),
+                _ => None,
+            },
+            _ => None,
+        }
+    })
+}
+
+/// Sets the sampling threshold (`th`) for the given sample rate in the OpenTelemetry entry of the
+/// trace state, keeping its other fields. The trace state is returned unchanged if that fails.
+fn with_sampling_threshold(trace_state: &TraceState, sample_rate: f64) -> TraceState {
+    let threshold = ((1.0 - sample_rate) * (1_u64 << 56) as f64).round() as u64;
+    let threshold = format!("{:014x}", threshold.min((1 << 56) - 1));
+    let threshold = match threshold.trim_end_matches('0') {
+        "" => "0",
+        threshold => threshold,
+    };
+    let value = trace_state
+        .get("ot")
+        .into_iter()
+        .flat_map(|value| value.split(';'))
+        .filter(|field| !field.starts_with("th:") && !field.starts_with("p:"))
+        .fold(format!("th:{}", threshold), |value, field| {
+            value + ";" + field
+        });
+
+    // `TraceState::insert` fails for keys, which don't exist yet, so rebuild the trace state with
+    // the new entry in front.
+    let header = trace_state.header();
+    let entries = header
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .filter(|(key, _)| *key != "ot");
+    TraceState::from_key_value(std::iter::once(("ot", value.as_str())).chain(entries))
+        .unwrap_or_else(|_| trace_state.clone())
+}
+
+fn parent_trace_state(parent_context: Option<&Context>) -> TraceState {
+    match parent_context {
+        Some(cx) => cx.span().span_context().trace_state().clone(),
+        None => TraceState::default(),
+    }
+}
+
+fn is_sampled_in(trace_id: TraceId, percentage: f64) -> bool {
+    percentage >= 100.0 || sampling_score(&trace_id_to_string(trace_id)) < percentage
+}
+
+/// Score between 0 and 100 of the operation id. Items with a score below the sampling percentage
+/// are sampled.
+fn sampling_score(operation_id: &str) -> f64 {
+    f64::from(sampling_hash(operation_id)) / f64::from(i32::MAX) * 100.0
+}
+
+/// djb2 hash of the operation id as computed by the Application Insights SDKs. The input is
+/// repeated to at least 8 characters and the hash is computed on UTF-16 code units with 32 bit
+/// overflow.
+fn sampling_hash(operation_id: &str) -> i32 {
+    if operation_id.is_empty() {
+        return 0;
+    }
+
+    let mut input = operation_id.to_string();
+    while input.len() < 8 {
+        input = input.repeat(2);
+    }
+    let hash = input.encode_utf16().fold(5381_i32, |hash, c| {
+        (hash << 5).wrapping_add(hash).wrapping_add(i32::from(c))
+    });
+
+    if hash == i32::MIN {
+        i32::MAX
+    } else {
+        hash.abs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanContext, SpanId, TRACE_FLAG_SAMPLED};
+    use test_case::test_case;
+
+    #[test_case("", 0 ; "empty")]
+    #[test_case("a", 348946573 ; "short")]
+    #[test_case("0123456789abcdef0123456789abcdef", 552514569 ; "ascending")]
+    #[test_case("4bf92f3577b34da6a3ce929d0e0e4736", 718577102 ; "random")]
+    fn hash(operation_id: &str, expected: i32) {
+        assert_eq!(expected, sampling_hash(operation_id));
+    }
+
+    #[test]
+    fn adaptive_sampler_decreases_sample_rate() {
+        let sampler = AdaptiveSampler::new(1.0).with_evaluation_interval(Duration::from_secs(10));
+        let start = sampler.state.lock().unwrap().interval_start;
+        // 10 spans per second during the first interval.
+        for _ in 0..99 {
+            sampler.record_items(1, start + Duration::from_secs(1));
+        }
+        sampler.record_items(1, start + Duration::from_secs(10));
+        assert_eq!(100.0, sampler.percentage());
+
+        // The sample rate is adjusted after the decrease timeout.
+        for _ in 0..1099 {
+            sampler.record_items(1, start + Duration::from_secs(11));
+        }
+        sampler.record_items(1, start + DECREASE_TIMEOUT);
+        assert_eq!(10.0, sampler.percentage());
+    }
+
+    #[test]
+    fn adaptive_sampler_increases_sample_rate() {
+        let sampler = AdaptiveSampler::new(10.0)
+            .with_initial_sample_rate(0.1)
+            .with_sample_rate_bounds(0.01, 0.5);
+        let start = sampler.state.lock().unwrap().interval_start;
+        sampler.record_items(1, start + DECREASE_TIMEOUT);
+        assert_eq!(10.0, sampler.percentage());
+
+        sampler.record_items(1, start + INCREASE_TIMEOUT);
+        assert_eq!(50.0, sampler.percentage());
+    }
+
+    #[test]
+    fn adaptive_sampler_records_sample_rate() {
+        let sampler = AdaptiveSampler::new(10.0).with_initial_sample_rate(0.5);
+        let result = sampler.should_sample(
+            None,
+            // Score of this trace id is 33.46.
+            TraceId::from_u128(0x4bf92f3577b34da6a3ce929d0e0e4736),
+            "name",
+            &SpanKind::Internal,
+            &[],
+            &[],
+        );
+        assert_eq!(SamplingDecision::RecordAndSample, result.decision);
+        assert_eq!(vec![SAMPLE_RATE.f64(0.5)], result.attributes);
+        assert_eq!(Some("th:8"), result.trace_state.get("ot"));
+    }
+
+    #[test]
+    fn adaptive_sampler_counts_items() {
+        let sampler = AdaptiveSampler::new(1.0).with_evaluation_interval(Duration::from_secs(10));
+        let start = sampler.state.lock().unwrap().interval_start;
+        sampler.record_items(100, start + Duration::from_secs(10));
+        sampler.record_items(1100, start + DECREASE_TIMEOUT);
+        assert_eq!(10.0, sampler.percentage());
+    }
+
+    #[test]
+    fn adaptive_sampler_clamps_sample_rate() {
+        let sampler = AdaptiveSampler::new(1.0)
+            .with_initial_sample_rate(0.01)
+            .with_sample_rate_bounds(0.3, 1.0);
+        assert_eq!(30.0, sampler.percentage());
+
+        let sampler = AdaptiveSampler::new(3.0).with_sample_rate_bounds(0.3, 1.0);
+        let start = sampler.state.lock().unwrap().interval_start;
+        // A sample rate of 0.3 would be rounded to 0.25, which is below the minimum.
+        sampler.record_items(1200, start + DECREASE_TIMEOUT);
+        assert_eq!(30.0, sampler.percentage());
+    }
+
+    #[test_case(true,  SamplingDecision::RecordAndSample ; "sampled parent")]
+    #[test_case(false, SamplingDecision::Drop            ; "dropped parent")]
+    fn adaptive_sampler_follows_parent(sampled: bool, expected: SamplingDecision) {
+        let parent = SpanContext::new(
+            TraceId::from_u128(0x4bf92f3577b34da6a3ce929d0e0e4736),
+            SpanId::from_u64(1),
+            if sampled { TRACE_FLAG_SAMPLED } else { 0 },
+            true,
+            TraceState::from_key_value(vec![("ot", "th:c")]).unwrap(),
+        );
+        let cx = Context::new().with_remote_span_context(parent);
+        // The sample rate would sample the trace, if it didn't follow the parent.
+        let result = AdaptiveSampler::new(10.0)
+            .with_initial_sample_rate(0.5)
+            .should_sample(
+                Some(&cx),
+                TraceId::from_u128(0x4bf92f3577b34da6a3ce929d0e0e4736),
+                "name",
+                &SpanKind::Internal,
+                &[],
+                &[],
+            );
+        assert_eq!(expected, result.decision);
+        assert!(result.attributes.is_empty());
+        assert_eq!(Some("th:c"), result.trace_state.get("ot"));
+    }
+
+    #[test_case("",                           1.0,       "ot=th:0"                     ; "empty")]
+    #[test_case("vendor=value",               0.25,      "ot=th:c,vendor=value"        ; "other vendor")]
+    #[test_case("ot=p:1;rv:123,vendor=value", 0.5,       "ot=th:8;rv:123,vendor=value" ; "existing")]
+    #[test_case("",                           1.0 / 3.0, "ot=th:aaaaaaaaaaaab"         ; "third")]
+    fn sampling_threshold(trace_state: &str, sample_rate: f64, expected: &str) {
+        let trace_state = TraceState::from_key_value(
+            trace_state
+                .split(',')
+                .filter_map(|entry| entry.split_once('=')),
+        )
+        .unwrap();
+        let trace_state = with_sampling_threshold(&trace_state, sample_rate);
+        assert_eq!(expected, trace_state.header());
+        let ot = trace_state.get("ot").unwrap();
+        assert!((sample_rate - parse_ot_trace_state(ot).unwrap()).abs() < 1e-9);
+    }
+
+    #[test_case("th:0",          Some(1.0)  ; "threshold all")]
+    #[test_case("th:8",          Some(0.5)  ; "threshold half")]
+    #[test_case("th:c",          Some(0.25) ; "threshold quarter")]
+    #[test_case("rv:123;th:c",   Some(0.25) ; "threshold with other fields")]
+    #[test_case("th:123456789abcdef", None  ; "threshold too long")]
+    #[test_case("p:0",           Some(1.0)  ; "p-value all")]
+    #[test_case("p:3;r:10",      Some(0.125); "p-value eighth")]
+    #[test_case("p:63",          None       ; "p-value zero")]
+    #[test_case("r:10",          None       ; "no probability")]
+    fn ot_trace_state(value: &str, expected: Option<f64>) {
+        assert_eq!(expected, parse_ot_trace_state(value));
+    }
+
+    #[test_case(0.0, false ; "none")]
+    #[test_case(0.33, false ; "below score")]
+    #[test_case(0.34, true ; "above score")]
+    #[test_case(1.0, true ; "all")]
+    fn should_sample(sample_rate: f64, sampled: bool) {
+        // Score of this trace id is 33.46.
+        let trace_id = TraceId::from_u128(0x4bf92f3577b34da6a3ce929d0e0e4736);
+        let result = ApplicationInsightsSampler::new(sample_rate).should_sample(
+            None,
+            trace_id,
+            "name",
+            &SpanKind::Internal,
+            &[],
+            &[],
+        );
+        assert_eq!(
+            if sampled {
+                SamplingDecision::RecordAndSample
+            } else {
+                SamplingDecision::Drop
+            },
+            result.decision
+        );
+    }
+}
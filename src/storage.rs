@@ -0,0 +1,296 @@
+use rand::Rng as _;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const EXTENSION_BATCH: &str = ".json";
+const EXTENSION_LOCK: &str = ".lock";
+const EXTENSION_TEMP: &str = ".tmp";
+
+/// Time for which a leased batch belongs to the process that leased it. If the process does not
+/// delete or release the batch within this time (e.g. because it crashed), other processes may
+/// lease it again.
+const LEASE_DURATION: Duration = Duration::from_secs(60);
+
+/// Directory based storage for serialized batches of telemetry, which could not be delivered.
+///
+/// Each batch is stored in its own file named `<created>-<random>.json`, where `<created>` is the
+/// creation time in nanoseconds since the UNIX epoch. Batches are written to a temporary file
+/// first and then renamed, so readers never see partially written batches.
+///
+/// Before a batch is sent, it is leased by renaming it to `<name>.json@<expiry>.lock`. Renaming is
+/// atomic, which makes sure that multiple processes sharing the same directory don't send the same
+/// batch twice.
+#[derive(Debug, Clone)]
+pub(crate) struct Storage {
+    directory: PathBuf,
+    pub(crate) limits: StorageLimits,
+}
+
+/// Limits of the offline storage.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StorageLimits {
+    /// Maximum total size of all stored batches in bytes.
+    pub(crate) max_size: u64,
+
+    /// Maximum age of a stored batch. Older batches are deleted instead of sent.
+    pub(crate) max_age: Duration,
+}
+
+impl Default for StorageLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 50 * 1024 * 1024,
+            max_age: Duration::from_secs(48 * 60 * 60),
+        }
+    }
+}
+
+/// A batch, which was leased from the storage. It must be deleted after it was sent successfully
+/// or released if it should be sent again later.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    path: PathBuf,
+    batch_name: String,
+}
+
+impl Storage {
+    pub(crate) fn new(directory: PathBuf, limits: StorageLimits) -> Self {
+        Self { directory, limits }
+    }
+
+    /// Stores a serialized batch of telemetry.
+    ///
+    /// Fails if storing the batch would exceed the maximum size of the storage.
+    pub(crate) fn store(&self, payload: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        if self.size()? + payload.len() as u64 > self.limits.max_size {
+            return Err(io::Error::other("offline storage is full"));
+        }
+
+        let name = format!(
+            "{:024}-{:016x}",
+            nanos_since_epoch(SystemTime::now()),
+            rand::thread_rng().gen::<u64>()
+        );
+        let temp_path = self.directory.join(format!("{}{}", name, EXTENSION_TEMP));
+        fs::write(&temp_path, payload)?;
+        fs::rename(
+            &temp_path,
+            self.directory.join(format!("{}{}", name, EXTENSION_BATCH)),
+        )
+    }
+
+    /// Leases the oldest batch, which is neither expired nor leased by anyone else. Expired
+    /// batches and abandoned temporary files are deleted along the way.
+    pub(crate) fn lease(&self) -> io::Result<Option<Lease>> {
+        let now = SystemTime::now();
+        let mut names: Vec<String> = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        // Names start with the creation time, which makes this oldest first.
+        names.sort();
+
+        for name in names {
+            let path = self.directory.join(&name);
+            let batch_name = if name.ends_with(EXTENSION_BATCH) {
+                name
+            } else if let Some(lock) = name.strip_suffix(EXTENSION_LOCK) {
+                match lock.rsplit_once('@') {
+                    Some((batch_name, expiry)) => match expiry.parse::<u128>() {
+                        Ok(expiry) if expiry > nanos_since_epoch(now) => continue,
+                        _ => batch_name.to_string(),
+                    },
+                    None => continue,
+                }
+            } else {
+                if name.ends_with(EXTENSION_TEMP) && self.is_expired(&name, now) {
+                    let _ = fs::remove_file(&path);
+                }
+                continue;
+            };
+
+            if self.is_expired(&batch_name, now) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+
+            let lease_path = self.directory.join(format!(
+                "{}@{}{}",
+                batch_name,
+                nanos_since_epoch(now + LEASE_DURATION),
+                EXTENSION_LOCK
+            ));
+            match fs::rename(&path, &lease_path) {
+                Ok(()) => {
+                    return Ok(Some(Lease {
+                        path: lease_path,
+                        batch_name,
+                    }))
+                }
+                // Another process leased the batch first.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Total size of all files in the storage in bytes.
+    fn size(&self) -> io::Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(&self.directory)? {
+            if let Ok(metadata) = entry?.metadata() {
+                size += metadata.len();
+            }
+        }
+
+        Ok(size)
+    }
+
+    fn is_expired(&self, name: &str, now: SystemTime) -> bool {
+        let created = name
+            .split('-')
+            .next()
+            .and_then(|created| created.parse::<u64>().ok())
+            .map(|created| UNIX_EPOCH + Duration::from_nanos(created));
+        match created {
+            Some(created) => now
+                .duration_since(created)
+                .map(|age| age > self.limits.max_age)
+                .unwrap_or(false),
+            // Not a file created by us. Leave it alone.
+            None => false,
+        }
+    }
+}
+
+impl Lease {
+    pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+        fs::read(&self.path)
+    }
+
+    /// Deletes the batch, e.g. after it was sent successfully or if it is corrupt.
+    pub(crate) fn delete(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+
+    /// Makes the batch available to be sent again.
+    pub(crate) fn release(self) -> io::Result<()> {
+        let directory = self.path.parent().unwrap_or_else(|| Path::new(""));
+        fs::rename(&self.path, directory.join(&self.batch_name))
+    }
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new() -> Self {
+            Self(std::env::temp_dir().join(format!(
+                "opentelemetry-application-insights-{:016x}",
+                rand::thread_rng().gen::<u64>()
+            )))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn store_and_lease() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        storage.store(b"first").unwrap();
+        storage.store(b"second").unwrap();
+
+        let first = storage.lease().unwrap().unwrap();
+        assert_eq!(b"first".to_vec(), first.read().unwrap());
+        let second = storage.lease().unwrap().unwrap();
+        assert_eq!(b"second".to_vec(), second.read().unwrap());
+        assert!(storage.lease().unwrap().is_none());
+
+        first.delete().unwrap();
+        second.release().unwrap();
+        let second = storage.lease().unwrap().unwrap();
+        assert_eq!(b"second".to_vec(), second.read().unwrap());
+        second.delete().unwrap();
+        assert!(file_names(&dir.0).is_empty());
+    }
+
+    #[test]
+    fn lease_without_directory() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        assert!(storage.lease().unwrap().is_none());
+    }
+
+    #[test]
+    fn abandoned_leases_can_be_leased_again() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        storage.store(b"batch").unwrap();
+        let lease = storage.lease().unwrap().unwrap();
+
+        // Simulate a process which crashed a long time ago while holding the lease.
+        let abandoned = dir
+            .0
+            .join(format!("{}@1{}", lease.batch_name, EXTENSION_LOCK));
+        fs::rename(&lease.path, &abandoned).unwrap();
+
+        let lease = storage.lease().unwrap().unwrap();
+        assert_eq!(b"batch".to_vec(), lease.read().unwrap());
+    }
+
+    #[test]
+    fn expired_batches_are_deleted() {
+        let dir = TempDir::new();
+        let mut storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        storage.store(b"batch").unwrap();
+        fs::write(dir.0.join("1-0000000000000000.tmp"), b"partial").unwrap();
+
+        storage.limits.max_age = Duration::from_secs(0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(storage.lease().unwrap().is_none());
+        assert!(file_names(&dir.0).is_empty());
+    }
+
+    #[test]
+    fn size_is_limited() {
+        let dir = TempDir::new();
+        let mut storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        storage.limits.max_size = 10;
+        storage.store(b"12345").unwrap();
+        assert!(storage.store(b"123456").is_err());
+        assert_eq!(1, file_names(&dir.0).len());
+    }
+}
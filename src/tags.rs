@@ -2,9 +2,11 @@ use crate::{
     convert::{span_id_to_string, trace_id_to_string},
     models::context_tag_keys::{self as tags, Tags, TAG_KEY_LOOKUP},
 };
+use opentelemetry::sdk::Resource;
 use opentelemetry::{
     sdk::export::trace::SpanData,
-    trace::{SpanId, SpanKind},
+    trace::{SpanContext, SpanId, SpanKind},
+    Key, Value,
 };
 use opentelemetry_semantic_conventions as semcov;
 
@@ -54,9 +56,43 @@ pub(crate) fn get_tags_for_span(span: &SpanData) -> Tags {
         map.insert(tags::USER_AUTH_USER_ID, user_id.as_str().into_owned());
     }
 
-    if let Some(service_name) = span.attributes.get(&semcov::resource::SERVICE_NAME) {
+    insert_resource_tags(&mut map, |key| span.attributes.get(key));
+
+    map
+}
+
+pub(crate) fn get_tags_for_event(span: &SpanData) -> Tags {
+    get_tags_for_span_context(&span.span_context)
+}
+
+/// Tags for telemetry, which happened inside of the span with the given context, e.g. events and
+/// log records.
+pub(crate) fn get_tags_for_span_context(span_context: &SpanContext) -> Tags {
+    let mut map = Tags::new();
+    map.insert(
+        tags::OPERATION_ID,
+        trace_id_to_string(span_context.trace_id()),
+    );
+    map.insert(
+        tags::OPERATION_PARENT_ID,
+        span_id_to_string(span_context.span_id()),
+    );
+    map
+}
+
+/// Tags for metrics and telemetry tracked without span, which only come from the resource.
+pub(crate) fn get_tags_for_resource(resource: &Resource) -> Tags {
+    let mut map = Tags::new();
+    insert_resource_tags(&mut map, |key| {
+        resource.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    });
+    map
+}
+
+fn insert_resource_tags<'a>(map: &mut Tags, get: impl Fn(&Key) -> Option<&'a Value>) {
+    if let Some(service_name) = get(&semcov::resource::SERVICE_NAME) {
         let mut cloud_role = service_name.as_str().into_owned();
-        if let Some(service_namespace) = span.attributes.get(&semcov::resource::SERVICE_NAMESPACE) {
+        if let Some(service_namespace) = get(&semcov::resource::SERVICE_NAMESPACE) {
             cloud_role.insert(0, '.');
             cloud_role.insert_str(0, &service_namespace.as_str());
         }
@@ -64,24 +100,22 @@ pub(crate) fn get_tags_for_span(span: &SpanData) -> Tags {
         map.insert(tags::CLOUD_ROLE, cloud_role);
     }
 
-    if let Some(service_instance) = span.attributes.get(&semcov::resource::SERVICE_INSTANCE_ID) {
+    if let Some(service_instance) = get(&semcov::resource::SERVICE_INSTANCE_ID) {
         map.insert(
             tags::CLOUD_ROLE_INSTANCE,
             service_instance.as_str().into_owned(),
         );
     }
 
-    if let Some(service_version) = span.attributes.get(&semcov::resource::SERVICE_VERSION) {
+    if let Some(service_version) = get(&semcov::resource::SERVICE_VERSION) {
         map.insert(
             tags::APPLICATION_VERSION,
             service_version.as_str().into_owned(),
         );
     }
 
-    if let Some(sdk_name) = span.attributes.get(&semcov::resource::TELEMETRY_SDK_NAME) {
-        let sdk_version = span
-            .attributes
-            .get(&semcov::resource::TELEMETRY_SDK_VERSION)
+    if let Some(sdk_name) = get(&semcov::resource::TELEMETRY_SDK_NAME) {
+        let sdk_version = get(&semcov::resource::TELEMETRY_SDK_VERSION)
             .map(|v| v.as_str())
             .unwrap_or_else(|| "0.0.0".into());
         map.insert(
@@ -89,19 +123,4 @@ pub(crate) fn get_tags_for_span(span: &SpanData) -> Tags {
             format!("{}:{}", sdk_name.as_str(), sdk_version),
         );
     }
-
-    map
-}
-
-pub(crate) fn get_tags_for_event(span: &SpanData) -> Tags {
-    let mut map = Tags::new();
-    map.insert(
-        tags::OPERATION_ID,
-        trace_id_to_string(span.span_context.trace_id()),
-    );
-    map.insert(
-        tags::OPERATION_PARENT_ID,
-        span_id_to_string(span.span_context.span_id()),
-    );
-    map
 }
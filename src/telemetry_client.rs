@@ -0,0 +1,479 @@
+use crate::{
+    batch::{BatchConfig, BatchSender},
+    convert::{duration_to_string, span_id_to_string, time_to_string},
+    exception::{error_attributes, take_exception_details},
+    models::{
+        context_tag_keys::Tags, AvailabilityData, Data, DataPoint, DataPointType, Envelope,
+        EventData, ExceptionData, Measurements, MessageData, MetricData, Properties,
+        RemoteDependencyData, RequestData,
+    },
+    tags::{get_tags_for_resource, get_tags_for_span_context},
+    uploader::Uploader,
+    AvailabilityResult, HttpClient, MeasurementPolicy, SeverityLevel,
+};
+use opentelemetry::{
+    runtime::Runtime,
+    sdk::Resource,
+    trace::{SpanId, TraceContextExt as _},
+    Context, Key, KeyValue, Value,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// A request tracked with `TelemetryClient::track_request`.
+#[derive(Debug, Clone)]
+pub struct RequestTelemetry {
+    /// Name of the request, e.g. `GET /users/:id`.
+    pub name: String,
+
+    /// Request URL with all query string parameters.
+    pub url: Option<String>,
+
+    /// Result of the request, e.g. the HTTP status code.
+    pub response_code: String,
+
+    /// Whether the request succeeded.
+    pub success: bool,
+
+    /// Time at which the request started.
+    pub start_time: SystemTime,
+
+    /// Duration of the request.
+    pub duration: Duration,
+
+    /// Attributes sent as custom properties and custom measurements.
+    pub attributes: Vec<KeyValue>,
+}
+
+/// A dependency call tracked with `TelemetryClient::track_dependency`.
+#[derive(Debug, Clone)]
+pub struct DependencyTelemetry {
+    /// Name of the command initiated with this dependency call, e.g. `GET /users/:id`.
+    pub name: String,
+
+    /// Dependency type, e.g. `HTTP` or `SQL`.
+    pub dependency_type: Option<String>,
+
+    /// Target site of the dependency call, e.g. the server name or host address.
+    pub target: Option<String>,
+
+    /// Command initiated by this dependency call, e.g. the SQL statement or the URL.
+    pub data: Option<String>,
+
+    /// Result of the dependency call, e.g. the HTTP status code.
+    pub result_code: Option<String>,
+
+    /// Whether the dependency call succeeded.
+    pub success: bool,
+
+    /// Time at which the dependency call started.
+    pub start_time: SystemTime,
+
+    /// Duration of the dependency call.
+    pub duration: Duration,
+
+    /// Attributes sent as custom properties and custom measurements.
+    pub attributes: Vec<KeyValue>,
+}
+
+/// Client for tracking telemetry without spans, e.g. during startup or in command line tools.
+///
+/// Telemetry is buffered and uploaded in batches from a background task. It's correlated with the
+/// current span, if there is one. Call `flush` or `flush_async` before your program exits to upload
+/// the remaining telemetry.
+#[derive(Debug)]
+pub struct TelemetryClient {
+    instrumentation_key: String,
+    tags: Tags,
+    measurement_policy: MeasurementPolicy,
+    sender: BatchSender,
+}
+
+impl TelemetryClient {
+    pub(crate) fn new<C, R>(
+        instrumentation_key: String,
+        client: Arc<C>,
+        uploader: Arc<Uploader>,
+        resource: Option<&Resource>,
+        measurement_policy: MeasurementPolicy,
+        runtime: R,
+    ) -> Self
+    where
+        C: HttpClient + 'static,
+        R: Runtime,
+    {
+        Self {
+            instrumentation_key,
+            tags: resource.map_or_else(Tags::new, get_tags_for_resource),
+            measurement_policy,
+            sender: BatchSender::spawn(client, uploader, BatchConfig::default(), runtime),
+        }
+    }
+
+    /// Track a custom event.
+    pub fn track_event(&self, name: &str, attributes: &[KeyValue]) {
+        let (properties, measurements) = self.properties_and_measurements(attributes);
+        self.track(
+            "Microsoft.ApplicationInsights.Event",
+            SystemTime::now(),
+            Data::Event(EventData {
+                ver: 2,
+                name: name.into(),
+                properties,
+                measurements,
+            }),
+        );
+    }
+
+    /// Track a trace message.
+    pub fn track_trace(
+        &self,
+        message: &str,
+        severity_level: SeverityLevel,
+        attributes: &[KeyValue],
+    ) {
+        let (properties, measurements) = self.properties_and_measurements(attributes);
+        self.track(
+            "Microsoft.ApplicationInsights.Message",
+            SystemTime::now(),
+            Data::Message(MessageData {
+                ver: 2,
+                message: message.into(),
+                severity_level: Some(severity_level),
+                properties,
+                measurements,
+            }),
+        );
+    }
+
+    /// Track an error as exception including its chain of sources (see `record_error`).
+    pub fn track_exception<E: Error + ?Sized>(&self, error: &E, attributes: &[KeyValue]) {
+        let error_attributes = error_attributes(error);
+        let mut attrs: HashMap<&Key, &Value> = error_attributes
+            .iter()
+            .chain(attributes)
+            .map(|kv| (&kv.key, &kv.value))
+            .collect();
+        let exceptions = take_exception_details(&mut attrs);
+        let mut properties = Some(
+            attrs
+                .iter()
+                .map(|(k, v)| (k.as_str().into(), (*v).into()))
+                .collect(),
+        )
+        .filter(|x: &Properties| !x.is_empty());
+        let measurements = self.measurement_policy.apply(attrs, &mut properties);
+        self.track(
+            "Microsoft.ApplicationInsights.Exception",
+            SystemTime::now(),
+            Data::Exception(ExceptionData {
+                ver: 2,
+                exceptions,
+                properties,
+                measurements,
+            }),
+        );
+    }
+
+    /// Track a single measurement of a metric. Attributes are sent as custom dimensions.
+    pub fn track_metric(&self, name: &str, value: f64, attributes: &[KeyValue]) {
+        self.track(
+            "Microsoft.ApplicationInsights.Metric",
+            SystemTime::now(),
+            Data::Metric(MetricData {
+                ver: 2,
+                metrics: vec![DataPoint {
+                    ns: None,
+                    name: name.into(),
+                    kind: DataPointType::Measurement,
+                    value,
+                    count: None,
+                    min: None,
+                    max: None,
+                    std_dev: None,
+                }],
+                properties: Some(
+                    attributes
+                        .iter()
+                        .map(|kv| (kv.key.as_str().into(), (&kv.value).into()))
+                        .collect(),
+                )
+                .filter(|x: &Properties| !x.is_empty()),
+            }),
+        );
+    }
+
+    /// Track an incoming request.
+    pub fn track_request(&self, request: RequestTelemetry) {
+        let (properties, measurements) = self.properties_and_measurements(&request.attributes);
+        self.track(
+            "Microsoft.ApplicationInsights.Request",
+            request.start_time,
+            Data::Request(RequestData {
+                ver: 2,
+                id: new_id().into(),
+                source: None,
+                name: Some(request.name.into()),
+                duration: duration_to_string(request.duration),
+                response_code: request.response_code.into(),
+                success: request.success,
+                url: request.url.map(Into::into),
+                properties,
+                measurements,
+            }),
+        );
+    }
+
+    /// Track an outgoing dependency call.
+    pub fn track_dependency(&self, dependency: DependencyTelemetry) {
+        let (properties, measurements) = self.properties_and_measurements(&dependency.attributes);
+        self.track(
+            "Microsoft.ApplicationInsights.RemoteDependency",
+            dependency.start_time,
+            Data::RemoteDependency(RemoteDependencyData {
+                ver: 2,
+                name: dependency.name.into(),
+                id: Some(new_id().into()),
+                result_code: dependency.result_code.map(Into::into),
+                duration: duration_to_string(dependency.duration),
+                success: Some(dependency.success),
+                data: dependency.data.map(Into::into),
+                target: dependency.target.map(Into::into),
+                type_: dependency.dependency_type.map(Into::into),
+                properties,
+                measurements,
+            }),
+        );
+    }
+
+    /// Track the result of an availability test.
+    pub fn track_availability(&self, result: AvailabilityResult) {
+        self.track(
+            "Microsoft.ApplicationInsights.Availability",
+            result.start_time,
+            Data::Availability(AvailabilityData {
+                ver: 2,
+                id: new_id().into(),
+                name: result.name.into(),
+                duration: duration_to_string(result.duration),
+                success: result.success,
+                run_location: Some(result.run_location.into()),
+                message: result.message.map(Into::into),
+                properties: None,
+                measurements: None,
+            }),
+        );
+    }
+
+    /// Upload all buffered telemetry. Waits at most 5 seconds.
+    ///
+    /// This blocks the current thread. Use `flush_async` in async code, especially on a
+    /// current-thread runtime, where blocking prevents the upload until the timeout passed.
+    pub fn flush(&self) {
+        self.sender.flush();
+    }
+
+    /// Upload all buffered telemetry without blocking the current thread. Waits at most 5 seconds.
+    pub async fn flush_async(&self) {
+        self.sender.flush_async().await;
+    }
+
+    fn properties_and_measurements(
+        &self,
+        attributes: &[KeyValue],
+    ) -> (Option<Properties>, Option<Measurements>) {
+        let mut properties = Some(
+            attributes
+                .iter()
+                .map(|kv| (kv.key.as_str().into(), (&kv.value).into()))
+                .collect(),
+        )
+        .filter(|x: &Properties| !x.is_empty());
+        let measurements = self.measurement_policy.apply(
+            attributes.iter().map(|kv| (&kv.key, &kv.value)),
+            &mut properties,
+        );
+        (properties, measurements)
+    }
+
+    fn track(&self, name: &'static str, time: SystemTime, data: Data) {
+        let mut tags = self.tags.clone();
+        let cx = Context::current();
+        let span = cx.span();
+        if span.span_context().is_valid() {
+            tags.extend(get_tags_for_span_context(span.span_context()));
+        }
+
+        // Telemetry is dropped if the buffer is full.
+        self.sender.send(Envelope {
+            name: name.into(),
+            time: time_to_string(time).into(),
+            sample_rate: None,
+            i_key: Some(self.instrumentation_key.clone().into()),
+            tags: Some(tags),
+            data: Some(data),
+        });
+    }
+}
+
+fn new_id() -> String {
+    span_id_to_string(SpanId::from_u64(rand::random()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_client::tests::RecordingClient,
+        storage::{tests::TempDir, Storage, StorageLimits},
+    };
+    use opentelemetry::trace::{SpanContext, TraceId, TraceState};
+    use std::convert::TryInto;
+
+    fn telemetry_client(client: &RecordingClient) -> TelemetryClient {
+        TelemetryClient::new(
+            "key".into(),
+            Arc::new(client.clone()),
+            Arc::new(Uploader::new(crate::DEFAULT_ENDPOINT.try_into().unwrap())),
+            Some(&Resource::new(vec![KeyValue::new("service.name", "cli")])),
+            MeasurementPolicy::Numeric,
+            opentelemetry::runtime::Tokio,
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tracks_telemetry() {
+        let client = RecordingClient::default();
+        let telemetry_client = telemetry_client(&client);
+        telemetry_client.track_event("signup", &[KeyValue::new("plan", "free")]);
+        telemetry_client.track_trace("starting", SeverityLevel::Information, &[]);
+        telemetry_client.track_exception(
+            &std::io::Error::other("failed"),
+            &[KeyValue::new("attempt", 2)],
+        );
+        telemetry_client.track_metric("queue_length", 7.0, &[KeyValue::new("queue", "jobs")]);
+        telemetry_client.track_request(RequestTelemetry {
+            name: "GET /".into(),
+            url: Some("http://localhost/".into()),
+            response_code: "200".into(),
+            success: true,
+            start_time: SystemTime::now(),
+            duration: Duration::from_millis(10),
+            attributes: vec![],
+        });
+        telemetry_client.track_dependency(DependencyTelemetry {
+            name: "SELECT".into(),
+            dependency_type: Some("SQL".into()),
+            target: Some("db".into()),
+            data: None,
+            result_code: None,
+            success: false,
+            start_time: SystemTime::now(),
+            duration: Duration::from_millis(10),
+            attributes: vec![],
+        });
+        telemetry_client.track_availability(AvailabilityResult {
+            name: "health".into(),
+            run_location: "local".into(),
+            success: true,
+            message: None,
+            start_time: SystemTime::now(),
+            duration: Duration::from_millis(10),
+        });
+        assert!(client.envelopes().is_empty());
+        telemetry_client.flush();
+
+        let envelopes = client.envelopes();
+        let base_types: Vec<_> = envelopes
+            .iter()
+            .map(|envelope| envelope["data"]["baseType"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "EventData",
+                "MessageData",
+                "ExceptionData",
+                "MetricData",
+                "RequestData",
+                "RemoteDependencyData",
+                "AvailabilityData",
+            ],
+            base_types
+        );
+        assert!(envelopes
+            .iter()
+            .all(|envelope| envelope["tags"]["ai.cloud.role"] == "cli"));
+        assert_eq!(
+            "free",
+            envelopes[0]["data"]["baseData"]["properties"]["plan"]
+        );
+        let exception = &envelopes[2]["data"]["baseData"];
+        assert_eq!("failed", exception["exceptions"][0]["message"]);
+        assert_eq!(2.0, exception["measurements"]["attempt"]);
+        let metric = &envelopes[3]["data"]["baseData"];
+        assert_eq!("queue_length", metric["metrics"][0]["name"]);
+        assert_eq!(7.0, metric["metrics"][0]["value"]);
+        assert_eq!("jobs", metric["properties"]["queue"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn correlates_with_current_span() {
+        let client = RecordingClient::default();
+        let telemetry_client = telemetry_client(&client);
+        let span_context = SpanContext::new(
+            TraceId::from_u128(314),
+            SpanId::from_u64(42),
+            0,
+            false,
+            TraceState::default(),
+        );
+        {
+            let _guard = Context::current()
+                .with_remote_span_context(span_context)
+                .attach();
+            telemetry_client.track_event("inside span", &[]);
+        }
+        telemetry_client.flush();
+
+        let envelopes = client.envelopes();
+        assert_eq!(1, envelopes.len());
+        assert_eq!(
+            "0000000000000000000000000000013a",
+            envelopes[0]["tags"]["ai.operation.id"]
+        );
+        assert_eq!(
+            "000000000000002a",
+            envelopes[0]["tags"]["ai.operation.parentId"]
+        );
+        assert_eq!("cli", envelopes[0]["tags"]["ai.cloud.role"]);
+    }
+
+    #[tokio::test]
+    async fn flush_async_sends_stored_telemetry_on_current_thread_runtime() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        storage.store(b"[{\"name\":\"stored\"}]").unwrap();
+        let mut uploader = Uploader::new(crate::DEFAULT_ENDPOINT.try_into().unwrap());
+        uploader.storage = Some(storage);
+        let client = RecordingClient::default();
+        let telemetry_client = TelemetryClient::new(
+            "key".into(),
+            Arc::new(client.clone()),
+            Arc::new(uploader),
+            None,
+            MeasurementPolicy::default(),
+            opentelemetry::runtime::Tokio,
+        );
+        telemetry_client.track_event("signup", &[]);
+        telemetry_client.flush_async().await;
+
+        let envelopes = client.envelopes();
+        assert_eq!(2, envelopes.len());
+        assert_eq!("Microsoft.ApplicationInsights.Event", envelopes[0]["name"]);
+        assert_eq!("stored", envelopes[1]["name"]);
+    }
+}
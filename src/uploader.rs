@@ -1,89 +1,774 @@
-use crate::{models::Envelope, Error, HttpClient};
-use http::{Request, Uri};
-use opentelemetry::sdk::export::trace::ExportResult;
-use serde::Deserialize;
+use crate::{auth::CachedCredential, storage::Storage, Error, HttpClient};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_timer::Delay;
+use futures_util::{future, stream, StreamExt as _};
+use http::{HeaderValue, Request, Response, Uri};
+use opentelemetry::{global, trace::TraceError};
+use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+use serde_json::value::{to_raw_value, RawValue};
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant, SystemTime},
+};
 
 const STATUS_OK: u16 = 200;
 const STATUS_PARTIAL_CONTENT: u16 = 206;
+const STATUS_TEMPORARY_REDIRECT: u16 = 307;
+const STATUS_PERMANENT_REDIRECT: u16 = 308;
+const STATUS_UNAUTHORIZED: u16 = 401;
+const STATUS_FORBIDDEN: u16 = 403;
 const STATUS_REQUEST_TIMEOUT: u16 = 408;
 const STATUS_TOO_MANY_REQUESTS: u16 = 429;
 const STATUS_APPLICATION_INACTIVE: u16 = 439; // Quota
 const STATUS_INTERNAL_SERVER_ERROR: u16 = 500;
 const STATUS_SERVICE_UNAVAILABLE: u16 = 503;
 
-#[derive(Debug, Deserialize)]
+/// Interval in which telemetry from the offline storage is sent in the background.
+const STORAGE_DRAIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Result of an upload as reported by Application Insights.
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct Transmission {
-    items_received: usize,
-    items_accepted: usize,
-    errors: Vec<TransmissionItem>,
+#[non_exhaustive]
+pub struct Transmission {
+    /// Number of telemetry items received.
+    pub items_received: usize,
+
+    /// Number of telemetry items accepted.
+    pub items_accepted: usize,
+
+    /// Errors for the telemetry items, which were not accepted.
+    pub errors: Vec<TransmissionItem>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Error for a single telemetry item, which Application Insights did not accept.
+#[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct TransmissionItem {
-    index: usize,
-    status_code: u16,
-    message: String,
-}
-
-/// Sends a telemetry items to the server.
-pub(crate) async fn send(
-    client: &dyn HttpClient,
-    endpoint: &Uri,
-    items: Vec<Envelope>,
-) -> ExportResult {
-    let payload = serde_json::to_vec(&items).map_err(Error::UploadSerializeRequest)?;
-    let request = Request::post(endpoint)
-        .header(http::header::CONTENT_TYPE, "application/json")
-        .body(payload)
-        .expect("request should be valid");
-
-    // TODO Implement retries
-    let response = client
-        .send(request)
-        .await
-        .map_err(Error::UploadConnection)?;
-    match response.status().as_u16() {
-        STATUS_OK => Ok(()),
-        status @ STATUS_PARTIAL_CONTENT => {
-            let content: Transmission = serde_json::from_slice(response.body())
-                .map_err(Error::UploadDeserializeResponse)?;
-            if content.items_received == content.items_accepted {
-                Ok(())
-            } else if content.errors.iter().any(|item| can_retry_item(item)) {
-                Err(format!("Upload error {}. Some items may be retried. However we don't currently support this.", status).into())
+#[non_exhaustive]
+pub struct TransmissionItem {
+    /// Index of the telemetry item in the upload.
+    pub index: usize,
+
+    /// Status code for this telemetry item. Items with status 206, 408, 429, 439, 500 or 503
+    /// may be accepted when sent again.
+    pub status_code: u16,
+
+    /// Error message.
+    pub message: String,
+}
+
+/// Controls how often and for how long failed uploads are retried.
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub(crate) max_attempts: u32,
+
+    /// Maximum time spent on one upload, including all retries.
+    pub(crate) timeout: Duration,
+
+    /// Backoff before the first retry. This doubles after every attempt.
+    pub(crate) initial_backoff: Duration,
+
+    /// Upper bound for the backoff between two attempts.
+    pub(crate) max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            timeout: Duration::from_secs(20),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with equal jitter for the given (1-based) attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+        half + Duration::from_millis(jitter)
+    }
+}
+
+/// Maximum number of redirects followed for one request.
+const MAX_REDIRECTS: usize = 10;
+
+/// Time for which a redirected endpoint is used for subsequent uploads, unless the redirect
+/// response specifies a different time with `Cache-Control: max-age`.
+const DEFAULT_REDIRECT_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Domains of Application Insights endpoints. Besides the configured endpoint, the token of the
+/// credential is only sent to hosts in these domains.
+const TRUSTED_DOMAINS: [&str; 2] = [
+    ".applicationinsights.azure.com",
+    ".services.visualstudio.com",
+];
+
+/// Limits the size of upload requests. Larger exports are split into multiple requests.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchLimits {
+    /// Maximum number of telemetry items per request.
+    pub(crate) max_items: usize,
+
+    /// Maximum size of the serialized telemetry items per request in bytes (before compression).
+    pub(crate) max_size: usize,
+
+    /// Maximum number of requests sent at the same time.
+    pub(crate) max_concurrent_requests: usize,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_items: 1000,
+            max_size: 4 * 1024 * 1024,
+            max_concurrent_requests: 1,
+        }
+    }
+}
+
+impl BatchLimits {
+    /// Serializes the items and splits them into batches, which don't exceed the limits. An item,
+    /// which exceeds the size limit on its own, is put into a batch of its own.
+    fn split<T: Serialize>(&self, items: Vec<T>) -> Result<Vec<Vec<Box<RawValue>>>, Error> {
+        // Size of the surrounding brackets.
+        const EMPTY_SIZE: usize = 2;
+
+        let mut batches = Vec::new();
+        let mut batch = Vec::new();
+        let mut batch_size = EMPTY_SIZE;
+        for item in items {
+            let item = to_raw_value(&item).map_err(Error::UploadSerializeRequest)?;
+            // Items after the first one are preceded by a comma.
+            let item_size = item.get().len() + 1;
+            if !batch.is_empty()
+                && (batch.len() >= self.max_items || batch_size + item_size > self.max_size)
+            {
+                batches.push(std::mem::take(&mut batch));
+                batch_size = EMPTY_SIZE;
+            }
+            batch_size += if batch.is_empty() {
+                item_size - 1
             } else {
-                Err(format!(
-                    "Upload error {}. No retry possible. Response: {:?}",
-                    status, content
-                )
-                .into())
+                item_size
+            };
+            batch.push(item);
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+
+        Ok(batches)
+    }
+}
+
+enum Outcome {
+    Success,
+    PartialSuccess {
+        status: u16,
+        transmission: Transmission,
+        retry_after: Option<Duration>,
+    },
+    Retry {
+        error: Error,
+        retry_after: Option<Duration>,
+    },
+    Unauthorized(Error),
+    Failure(Error),
+}
+
+/// Sends telemetry from the offline storage at startup and then periodically. Stops once the
+/// uploader was dropped. Errors are reported to the global error handler.
+pub(crate) async fn drain_storage<C: HttpClient>(client: Arc<C>, uploader: Weak<Uploader>) {
+    loop {
+        match uploader.upgrade() {
+            Some(uploader) => {
+                if let Err(err) = uploader.send_stored(&*client, usize::MAX).await {
+                    global::handle_error(TraceError::from(err));
+                }
             }
+            None => break,
         }
-        status @ STATUS_REQUEST_TIMEOUT
-        | status @ STATUS_TOO_MANY_REQUESTS
-        | status @ STATUS_APPLICATION_INACTIVE
-        | status @ STATUS_SERVICE_UNAVAILABLE => {
-            // TODO Implement retries
-            Err(format!("Upload error {}. Retry possible", status).into())
+        Delay::new(STORAGE_DRAIN_INTERVAL).await;
+    }
+}
+
+/// Sends telemetry to Application Insights.
+#[derive(Debug)]
+pub(crate) struct Uploader {
+    pub(crate) endpoint: Uri,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) batch_limits: BatchLimits,
+    pub(crate) storage: Option<Storage>,
+    pub(crate) credential: Option<CachedCredential>,
+    #[cfg(feature = "compression")]
+    pub(crate) compression: Option<flate2::Compression>,
+    redirect: Mutex<Option<Redirect>>,
+}
+
+/// Endpoint Application Insights redirected to.
+#[derive(Debug, Clone)]
+struct Redirect {
+    endpoint: Uri,
+    expires: Instant,
+}
+
+impl Clone for Uploader {
+    fn clone(&self) -> Self {
+        Self {
+            endpoint: self.endpoint.clone(),
+            retry_policy: self.retry_policy.clone(),
+            batch_limits: self.batch_limits.clone(),
+            storage: self.storage.clone(),
+            credential: self.credential.clone(),
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            redirect: Mutex::new(self.redirect.lock().unwrap().clone()),
         }
-        status @ STATUS_INTERNAL_SERVER_ERROR => {
-            if let Ok(content) = serde_json::from_slice::<Transmission>(response.body()) {
-                if content.errors.iter().any(|item| can_retry_item(item)) {
-                    Err(format!("Upload error {}. Some items may be retried. However we don't currently support this.", status).into())
+    }
+}
+
+impl Uploader {
+    pub(crate) fn new(endpoint: Uri) -> Self {
+        Self {
+            endpoint,
+            retry_policy: RetryPolicy::default(),
+            batch_limits: BatchLimits::default(),
+            storage: None,
+            credential: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            redirect: Mutex::new(None),
+        }
+    }
+
+    /// Sends telemetry items to the server. Items are split into batches according to the batch
+    /// limits, which are sent in separate requests. The result contains the errors of all failed
+    /// requests.
+    pub(crate) async fn send<T: Serialize>(
+        &self,
+        client: &dyn HttpClient,
+        items: Vec<T>,
+    ) -> Result<(), Error> {
+        let batches = self.batch_limits.split(items)?;
+        let requests = batches.len();
+        let mut errors: Vec<Error> = stream::iter(batches)
+            .map(|batch| self.send_batch(client, batch))
+            .buffer_unordered(self.batch_limits.max_concurrent_requests.max(1))
+            .filter_map(|result| future::ready(result.err()))
+            .collect()
+            .await;
+
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::UploadRequests { requests, errors }),
+        }
+    }
+
+    /// Sends a batch of telemetry items to the server. Transient failures are retried according
+    /// to the retry policy. If the server accepts only some of the items, the retryable ones are
+    /// sent again and the rest is reported as rejected.
+    ///
+    /// Items, which could not be delivered because of a transient error or because the request was
+    /// not authorized, are saved to the offline storage if one is configured.
+    async fn send_batch(
+        &self,
+        client: &dyn HttpClient,
+        items: Vec<Box<RawValue>>,
+    ) -> Result<(), Error> {
+        let (result, undelivered) = self.send_with_retries(client, items).await;
+        let error = match result {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        match self.storage.as_ref() {
+            Some(storage) if !undelivered.is_empty() => {
+                let stored =
+                    serde_json::to_vec(&undelivered).map_err(Error::UploadSerializeRequest)?;
+                match storage.store(&stored) {
+                    Ok(()) => Err(Error::SavedToOfflineStorage {
+                        items: undelivered.len(),
+                        error: Box::new(error),
+                    }),
+                    Err(store_err) => {
+                        // Report the upload error as the result of the export. It's the more
+                        // important one.
+                        global::handle_error(TraceError::from(Error::OfflineStorage(store_err)));
+                        Err(error)
+                    }
+                }
+            }
+            _ => Err(error),
+        }
+    }
+
+    /// Sends up to `max_batches` batches from the offline storage, oldest first. Stops at the
+    /// first batch, which cannot be delivered because of a transient error. Does nothing if no
+    /// offline storage is configured.
+    pub(crate) async fn send_stored(
+        &self,
+        client: &dyn HttpClient,
+        max_batches: usize,
+    ) -> Result<(), Error> {
+        let storage = match self.storage.as_ref() {
+            Some(storage) => storage,
+            None => return Ok(()),
+        };
+
+        for _ in 0..max_batches {
+            let lease = match storage.lease().map_err(Error::OfflineStorage)? {
+                Some(lease) => lease,
+                None => break,
+            };
+            let items: Vec<serde_json::Value> = match lease
+                .read()
+                .ok()
+                .and_then(|payload| serde_json::from_slice(&payload).ok())
+            {
+                Some(items) => items,
+                None => {
+                    // The batch is corrupt. There is no way we can ever send it.
+                    lease.delete().map_err(Error::OfflineStorage)?;
+                    continue;
+                }
+            };
+
+            let stored_len = items.len();
+            let (result, undelivered) = self.send_with_retries(client, items).await;
+            if undelivered.is_empty() {
+                lease.delete().map_err(Error::OfflineStorage)?;
+                result?;
+            } else {
+                if undelivered.len() == stored_len {
+                    lease.release().map_err(Error::OfflineStorage)?;
                 } else {
-                    Err(format!("Upload error {}. No retry possible", status).into())
+                    let stored =
+                        serde_json::to_vec(&undelivered).map_err(Error::UploadSerializeRequest)?;
+                    storage.store(&stored).map_err(Error::OfflineStorage)?;
+                    lease.delete().map_err(Error::OfflineStorage)?;
                 }
+
+                return result;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends items and retries transient failures. Returns the result together with the items,
+    /// which could not be delivered because of a transient error or because the request was not
+    /// authorized.
+    async fn send_with_retries<T: Serialize + Send>(
+        &self,
+        client: &dyn HttpClient,
+        mut items: Vec<T>,
+    ) -> (Result<(), Error>, Vec<T>) {
+        let total = items.len();
+        // Position of each remaining item in the original batch. Used to report rejected items.
+        let mut positions: Vec<usize> = (0..total).collect();
+        let mut rejected: Vec<TransmissionItem> = Vec::new();
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let payload = match self.serialize(&items) {
+                Ok(payload) => payload,
+                Err(err) => return (Err(Error::UploadSerializeRequest(err)), Vec::new()),
+            };
+            let mut outcome = self.try_send(client, &payload).await;
+            if let (Outcome::Unauthorized(_), Some(credential)) = (&outcome, &self.credential) {
+                // The cached token may have been revoked. Get a new one and try once more.
+                credential.invalidate();
+                outcome = self.try_send(client, &payload).await;
+            }
+
+            let (error, retry_after) = match outcome {
+                Outcome::Success => return (finish(total, rejected, None, &positions), Vec::new()),
+                Outcome::Unauthorized(error) => {
+                    // The items are fine, only the credential isn't. Keep them, so they can be
+                    // sent once access is granted.
+                    let error = map_indices(error, &positions);
+                    return (finish(total, rejected, Some(error), &positions), items);
+                }
+                Outcome::Failure(error) => {
+                    let error = map_indices(error, &positions);
+                    return (finish(total, rejected, Some(error), &positions), Vec::new());
+                }
+                Outcome::Retry { error, retry_after } => {
+                    (map_indices(error, &positions), retry_after)
+                }
+                Outcome::PartialSuccess {
+                    status,
+                    transmission,
+                    retry_after,
+                } => {
+                    let mut retry = Vec::new();
+                    let mut retry_errors = Vec::new();
+                    for mut item in transmission.errors {
+                        if let Some(position) = positions.get(item.index) {
+                            let index = item.index;
+                            item.index = *position;
+                            if can_retry_item(&item) {
+                                retry.push(index);
+                                retry_errors.push(item);
+                            } else {
+                                rejected.push(item);
+                            }
+                        }
+                    }
+
+                    if retry.is_empty() {
+                        return (finish(total, rejected, None, &positions), Vec::new());
+                    }
+
+                    let error = Error::UploadStatus {
+                        status,
+                        retryable: true,
+                        retry_after,
+                        transmission: Some(Transmission {
+                            items_received: transmission.items_received,
+                            items_accepted: transmission.items_accepted,
+                            errors: retry_errors,
+                        }),
+                    };
+                    positions = retain(positions, &retry);
+                    items = retain(items, &retry);
+                    (error, retry_after)
+                }
+            };
+
+            if attempt >= self.retry_policy.max_attempts {
+                return (finish(total, rejected, Some(error), &positions), items);
+            }
+
+            let delay = retry_after.unwrap_or_else(|| self.retry_policy.backoff(attempt));
+            if started.elapsed() + delay > self.retry_policy.timeout {
+                return (finish(total, rejected, Some(error), &positions), items);
+            }
+
+            Delay::new(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Serializes the items to JSON and compresses them if compression is enabled.
+    fn serialize<T: Serialize>(&self, items: &[T]) -> Result<Vec<u8>, serde_json::Error> {
+        #[cfg(feature = "compression")]
+        if let Some(level) = self.compression {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+            serde_json::to_writer(&mut encoder, items)?;
+            return encoder.finish().map_err(serde_json::Error::io);
+        }
+
+        serde_json::to_vec(items)
+    }
+
+    fn is_compressed(&self) -> bool {
+        #[cfg(feature = "compression")]
+        return self.compression.is_some();
+        #[cfg(not(feature = "compression"))]
+        return false;
+    }
+
+    /// Sends the serialized items once. Follows redirects.
+    async fn try_send(&self, client: &dyn HttpClient, payload: &[u8]) -> Outcome {
+        let authorization = match &self.credential {
+            Some(credential) => match credential
+                .token()
+                .await
+                .and_then(|token| Ok(HeaderValue::from_str(&format!("Bearer {}", token))?))
+            {
+                Ok(authorization) => Some(authorization),
+                Err(err) => {
+                    return Outcome::Retry {
+                        error: Error::Authentication(err),
+                        retry_after: None,
+                    }
+                }
+            },
+            None => None,
+        };
+
+        let mut endpoint = self.current_endpoint();
+        let mut visited = Vec::new();
+        loop {
+            let mut request =
+                Request::post(&endpoint).header(http::header::CONTENT_TYPE, "application/json");
+            if self.is_compressed() {
+                request = request.header(http::header::CONTENT_ENCODING, "gzip");
+            }
+            if let Some(authorization) = &authorization {
+                // Don't leak the token to hosts we were redirected to, unless they belong to
+                // Application Insights.
+                if is_trusted_endpoint(&self.endpoint, &endpoint) {
+                    request = request.header(http::header::AUTHORIZATION, authorization);
+                }
+            }
+            let request = request
+                .body(payload.to_vec())
+                .expect("request should be valid");
+            let response = match client.send(request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    return Outcome::Retry {
+                        error: Error::UploadConnection(err),
+                        retry_after: None,
+                    }
+                }
+            };
+
+            let status = response.status().as_u16();
+            if status != STATUS_TEMPORARY_REDIRECT && status != STATUS_PERMANENT_REDIRECT {
+                return handle_response(response);
+            }
+
+            let location = response
+                .headers()
+                .get(http::header::LOCATION)
+                .and_then(|location| location.to_str().ok());
+            let next = location.and_then(|location| resolve_location(&endpoint, location));
+            visited.push(endpoint);
+            match next {
+                Some(next) if visited.len() <= MAX_REDIRECTS && !visited.contains(&next) => {
+                    *self.redirect.lock().unwrap() = Some(Redirect {
+                        endpoint: next.clone(),
+                        expires: Instant::now() + redirect_duration(&response),
+                    });
+                    endpoint = next;
+                }
+                _ => {
+                    // Start over with the configured endpoint next time.
+                    *self.redirect.lock().unwrap() = None;
+                    return Outcome::Failure(Error::UploadRedirect {
+                        location: location.map(Into::into),
+                    });
+                }
+            }
+        }
+    }
+
+    /// The endpoint Application Insights redirected to recently or the configured one.
+    fn current_endpoint(&self) -> Uri {
+        let mut redirect = self.redirect.lock().unwrap();
+        match redirect.as_ref() {
+            Some(redirect) if redirect.expires > Instant::now() => redirect.endpoint.clone(),
+            _ => {
+                *redirect = None;
+                self.endpoint.clone()
+            }
+        }
+    }
+}
+
+/// Resolves the `Location` header of a redirect, which may be relative to the current endpoint.
+fn resolve_location(endpoint: &Uri, location: &str) -> Option<Uri> {
+    let location: Uri = location.parse().ok()?;
+    let uri = if location.scheme().is_some() {
+        location
+    } else {
+        let mut parts = location.into_parts();
+        parts.scheme = endpoint.scheme().cloned();
+        parts.authority = endpoint.authority().cloned();
+        Uri::from_parts(parts).ok()?
+    };
+
+    match uri.scheme_str() {
+        Some("http") | Some("https") if uri.host().is_some() => Some(uri),
+        _ => None,
+    }
+}
+
+/// Whether the token of the credential may be sent to the endpoint, i.e. it's the configured
+/// endpoint or an HTTPS endpoint of Application Insights.
+fn is_trusted_endpoint(configured: &Uri, endpoint: &Uri) -> bool {
+    if endpoint.scheme() == configured.scheme() && endpoint.authority() == configured.authority() {
+        return true;
+    }
+    let host = match endpoint.host() {
+        Some(host) => host.to_ascii_lowercase(),
+        None => return false,
+    };
+    endpoint.scheme_str() == Some("https")
+        && TRUSTED_DOMAINS.iter().any(|domain| host.ends_with(domain))
+}
+
+/// Parses `Cache-Control: max-age` of a redirect response.
+fn redirect_duration(response: &Response<Bytes>) -> Duration {
+    response
+        .headers()
+        .get(http::header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(',')
+                .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+                .find_map(|max_age| max_age.parse().ok())
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_REDIRECT_DURATION)
+}
+
+fn handle_response(response: Response<Bytes>) -> Outcome {
+    match response.status().as_u16() {
+        STATUS_OK => Outcome::Success,
+        status @ STATUS_PARTIAL_CONTENT => {
+            let transmission: Transmission = match serde_json::from_slice(response.body()) {
+                Ok(transmission) => transmission,
+                Err(err) => return Outcome::Failure(Error::UploadDeserializeResponse(err)),
+            };
+            if transmission.items_received == transmission.items_accepted {
+                Outcome::Success
             } else {
-                // TODO Implement retries
-                Err(format!("Upload error {}. Some items may be retried", status).into())
+                Outcome::PartialSuccess {
+                    status,
+                    transmission,
+                    retry_after: retry_after(&response),
+                }
+            }
+        }
+        STATUS_REQUEST_TIMEOUT
+        | STATUS_TOO_MANY_REQUESTS
+        | STATUS_APPLICATION_INACTIVE
+        | STATUS_SERVICE_UNAVAILABLE => Outcome::Retry {
+            error: status_error(&response, true),
+            retry_after: retry_after(&response),
+        },
+        status @ STATUS_INTERNAL_SERVER_ERROR => {
+            match serde_json::from_slice::<Transmission>(response.body()) {
+                Ok(transmission) if !transmission.errors.is_empty() => Outcome::PartialSuccess {
+                    status,
+                    transmission,
+                    retry_after: retry_after(&response),
+                },
+                _ => Outcome::Retry {
+                    error: status_error(&response, true),
+                    retry_after: retry_after(&response),
+                },
+            }
+        }
+        STATUS_UNAUTHORIZED | STATUS_FORBIDDEN => {
+            Outcome::Unauthorized(status_error(&response, false))
+        }
+        _ => Outcome::Failure(status_error(&response, false)),
+    }
+}
+
+fn status_error(response: &Response<Bytes>, retryable: bool) -> Error {
+    Error::UploadStatus {
+        status: response.status().as_u16(),
+        retryable,
+        retry_after: retry_after(response),
+        // Application Insights explains some errors (e.g. an invalid instrumentation key) per
+        // item, even if the request failed as a whole.
+        transmission: serde_json::from_slice(response.body()).ok(),
+    }
+}
+
+/// Maps the item indices in the error from positions in the last attempt to positions in the
+/// original batch.
+fn map_indices(error: Error, positions: &[usize]) -> Error {
+    match error {
+        Error::UploadStatus {
+            status,
+            retryable,
+            retry_after,
+            transmission: Some(mut transmission),
+        } => {
+            transmission.errors = transmission
+                .errors
+                .into_iter()
+                .filter_map(|mut item| {
+                    item.index = *positions.get(item.index)?;
+                    Some(item)
+                })
+                .collect();
+            Error::UploadStatus {
+                status,
+                retryable,
+                retry_after,
+                transmission: Some(transmission),
             }
         }
-        status => Err(format!("Upload error {}. No retry possible", status).into()),
+        error => error,
     }
 }
 
+/// Combines the permanently rejected items and the error of the last attempt (if any) into the
+/// final result of uploading `total` items. `remaining` are the positions of the items sent in
+/// the last attempt.
+fn finish(
+    total: usize,
+    rejected: Vec<TransmissionItem>,
+    error: Option<Error>,
+    remaining: &[usize],
+) -> Result<(), Error> {
+    let (status, retryable, retry_after, mut errors) = match error {
+        None if rejected.is_empty() => return Ok(()),
+        None => (STATUS_PARTIAL_CONTENT, false, None, Vec::new()),
+        Some(error) if rejected.is_empty() && remaining.len() == total => return Err(error),
+        Some(Error::UploadStatus {
+            status,
+            retryable,
+            retry_after,
+            transmission,
+        }) => {
+            let mut errors = transmission.map(|t| t.errors).unwrap_or_default();
+            // Items without a result of their own failed with the status of the response.
+            for &index in remaining {
+                if !errors.iter().any(|item| item.index == index) {
+                    errors.push(TransmissionItem {
+                        index,
+                        status_code: status,
+                        message: http::StatusCode::from_u16(status)
+                            .ok()
+                            .and_then(|status| status.canonical_reason())
+                            .unwrap_or_default()
+                            .into(),
+                    });
+                }
+            }
+            (status, retryable, retry_after, errors)
+        }
+        // Connection or authentication errors don't have results per item. The rejected items
+        // are only reported as part of the last attempt's error in this case.
+        Some(error) => return Err(error),
+    };
+
+    errors.extend(rejected);
+    errors.sort_by_key(|item| item.index);
+    Err(Error::UploadStatus {
+        status,
+        retryable,
+        retry_after,
+        transmission: Some(Transmission {
+            items_received: total,
+            items_accepted: total.saturating_sub(errors.len()),
+            errors,
+        }),
+    })
+}
+
+/// Keeps only the elements at the given indices.
+fn retain<T>(items: Vec<T>, indices: &[usize]) -> Vec<T> {
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| indices.contains(index))
+        .map(|(_, item)| item)
+        .collect()
+}
+
 /// Determines that a telemetry item can be re-send corresponding to this submission status
 /// descriptor.
 fn can_retry_item(item: &TransmissionItem) -> bool {
@@ -94,3 +779,828 @@ fn can_retry_item(item: &TransmissionItem) -> bool {
         || item.status_code == STATUS_INTERNAL_SERVER_ERROR
         || item.status_code == STATUS_SERVICE_UNAVAILABLE
 }
+
+pub(crate) fn describe_items(items: &[TransmissionItem]) -> String {
+    items
+        .iter()
+        .map(|item| format!("[{}] {} {}", item.index, item.status_code, item.message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parses the `Retry-After` header, which contains either a number of seconds or an HTTP date.
+fn retry_after(response: &Response<Bytes>) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date: SystemTime = DateTime::parse_from_rfc2822(value)
+        .ok()?
+        .with_timezone(&Utc)
+        .into();
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::tests::CountingCredential,
+        models::Envelope,
+        storage::{tests::TempDir, StorageLimits},
+    };
+    use async_trait::async_trait;
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    #[derive(Debug)]
+    struct ScriptedClient {
+        responses: Mutex<VecDeque<Response<Bytes>>>,
+        requests: Mutex<Vec<Request<Vec<u8>>>>,
+    }
+
+    impl ScriptedClient {
+        fn new(responses: Vec<Response<Bytes>>) -> Self {
+            Self {
+                responses: Mutex::new(responses.into()),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.lock().unwrap().len()
+        }
+
+        /// Authorization headers of all requests. Empty for requests without one.
+        fn authorizations(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    request
+                        .headers()
+                        .get(http::header::AUTHORIZATION)
+                        .map(|authorization| authorization.to_str().unwrap().to_string())
+                        .unwrap_or_default()
+                })
+                .collect()
+        }
+
+        /// URIs of all requests.
+        fn uris(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|request| request.uri().to_string())
+                .collect()
+        }
+
+        /// Names of the envelopes sent in the n-th request.
+        fn sent_names(&self, n: usize) -> Vec<String> {
+            let requests = self.requests.lock().unwrap();
+            let items: Vec<serde_json::Value> = serde_json::from_slice(requests[n].body()).unwrap();
+            items
+                .iter()
+                .map(|item| item["name"].as_str().unwrap().to_string())
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for ScriptedClient {
+        async fn send(
+            &self,
+            request: Request<Vec<u8>>,
+        ) -> Result<Response<Bytes>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+            self.requests.lock().unwrap().push(request);
+            self.responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| "no more scripted responses".into())
+        }
+    }
+
+    fn status(status: u16) -> Response<Bytes> {
+        Response::builder()
+            .status(status)
+            .body(Bytes::new())
+            .unwrap()
+    }
+
+    fn transmission(status: u16, body: serde_json::Value) -> Response<Bytes> {
+        Response::builder()
+            .status(status)
+            .body(body.to_string().into())
+            .unwrap()
+    }
+
+    fn envelopes(names: &[&str]) -> Vec<Envelope> {
+        names
+            .iter()
+            .map(|name| Envelope {
+                name: (*name).into(),
+                time: "2020-06-21:10:40:00Z".into(),
+                sample_rate: None,
+                i_key: None,
+                tags: None,
+                data: None,
+            })
+            .collect()
+    }
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            timeout: Duration::from_secs(5),
+            initial_backoff: Duration::from_millis(2),
+            max_backoff: Duration::from_millis(10),
+        }
+    }
+
+    fn uploader(retry_policy: RetryPolicy, storage: Option<Storage>) -> Uploader {
+        let mut uploader = Uploader::new("http://localhost/v2/track".parse().unwrap());
+        uploader.retry_policy = retry_policy;
+        uploader.storage = storage;
+        uploader
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let client = ScriptedClient::new(vec![status(503), status(429), status(408), status(200)]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(4, client.requests());
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let client = ScriptedClient::new(vec![status(400), status(200)]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(1, client.requests());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let client = ScriptedClient::new((0..10).map(|_| status(500)).collect());
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..fast_policy()
+        };
+        let result = uploader(policy.clone(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(3, client.requests());
+    }
+
+    #[tokio::test]
+    async fn retries_connection_errors() {
+        let client = ScriptedClient::new(vec![]);
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            ..fast_policy()
+        };
+        let result = uploader(policy.clone(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(2, client.requests());
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_retry_after_exceeds_timeout() {
+        let response = Response::builder()
+            .status(429)
+            .header(http::header::RETRY_AFTER, "120")
+            .body(Bytes::new())
+            .unwrap();
+        let client = ScriptedClient::new(vec![response, status(200)]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(1, client.requests());
+    }
+
+    #[tokio::test]
+    async fn resends_only_retryable_items_after_partial_success() {
+        let client = ScriptedClient::new(vec![
+            transmission(
+                206,
+                serde_json::json!({
+                    "itemsReceived": 4,
+                    "itemsAccepted": 1,
+                    "errors": [
+                        { "index": 1, "statusCode": 429, "message": "throttled" },
+                        { "index": 2, "statusCode": 400, "message": "invalid" },
+                        { "index": 3, "statusCode": 500, "message": "oops" },
+                    ],
+                }),
+            ),
+            transmission(
+                206,
+                serde_json::json!({
+                    "itemsReceived": 2,
+                    "itemsAccepted": 1,
+                    "errors": [
+                        { "index": 0, "statusCode": 503, "message": "unavailable" },
+                    ],
+                }),
+            ),
+            status(200),
+        ]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a", "b", "c", "d"]))
+            .await;
+
+        assert_eq!(3, client.requests());
+        assert_eq!(vec!["a", "b", "c", "d"], client.sent_names(0));
+        assert_eq!(vec!["b", "d"], client.sent_names(1));
+        assert_eq!(vec!["b"], client.sent_names(2));
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("[2] 400 invalid"), "{}", error);
+        match error {
+            Error::UploadStatus {
+                status: 206,
+                retryable: false,
+                transmission: Some(transmission),
+                ..
+            } => {
+                assert_eq!(4, transmission.items_received);
+                assert_eq!(3, transmission.items_accepted);
+                assert_eq!(1, transmission.errors.len());
+                assert_eq!(2, transmission.errors[0].index);
+                assert_eq!(400, transmission.errors[0].status_code);
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_status_and_retry_after() {
+        let response = Response::builder()
+            .status(439)
+            .header(http::header::RETRY_AFTER, "60")
+            .body(Bytes::new())
+            .unwrap();
+        let client = ScriptedClient::new(vec![response]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::UploadStatus {
+                status: 439,
+                retryable: true,
+                retry_after: Some(retry_after),
+                transmission: None,
+            }) if retry_after == Duration::from_secs(60)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_item_errors_of_failed_requests() {
+        let client = ScriptedClient::new(vec![transmission(
+            400,
+            serde_json::json!({
+                "itemsReceived": 1,
+                "itemsAccepted": 0,
+                "errors": [
+                    { "index": 0, "statusCode": 400, "message": "Invalid instrumentation key" },
+                ],
+            }),
+        )]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+
+        match result {
+            Err(Error::UploadStatus {
+                status: 400,
+                retryable: false,
+                transmission: Some(transmission),
+                ..
+            }) => {
+                assert_eq!(0, transmission.items_accepted);
+                assert_eq!(
+                    "Invalid instrumentation key",
+                    transmission.errors[0].message
+                );
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_undelivered_items_after_partial_success() {
+        let client = ScriptedClient::new(vec![
+            transmission(
+                206,
+                serde_json::json!({
+                    "itemsReceived": 3,
+                    "itemsAccepted": 1,
+                    "errors": [
+                        { "index": 1, "statusCode": 400, "message": "invalid" },
+                        { "index": 2, "statusCode": 429, "message": "throttled" },
+                    ],
+                }),
+            ),
+            status(503),
+        ]);
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            ..fast_policy()
+        };
+        let result = uploader(policy, None)
+            .send(&client, envelopes(&["a", "b", "c"]))
+            .await;
+
+        match result {
+            Err(Error::UploadStatus {
+                status: 503,
+                retryable: true,
+                transmission: Some(transmission),
+                ..
+            }) => {
+                assert_eq!(3, transmission.items_received);
+                assert_eq!(1, transmission.items_accepted);
+                let errors: Vec<_> = transmission
+                    .errors
+                    .iter()
+                    .map(|item| (item.index, item.status_code))
+                    .collect();
+                assert_eq!(vec![(1, 400), (2, 503)], errors);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_success_without_retryable_items_is_not_retried() {
+        let client = ScriptedClient::new(vec![transmission(
+            206,
+            serde_json::json!({
+                "itemsReceived": 2,
+                "itemsAccepted": 1,
+                "errors": [
+                    { "index": 0, "statusCode": 400, "message": "invalid" },
+                ],
+            }),
+        )]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a", "b"]))
+            .await;
+
+        assert_eq!(1, client.requests());
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn stores_undelivered_items_and_sends_them_later() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        let client = ScriptedClient::new(vec![status(503), status(503)]);
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            ..fast_policy()
+        };
+        let result = uploader(policy.clone(), Some(storage.clone()))
+            .send(&client, envelopes(&["a", "b"]))
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::SavedToOfflineStorage { items: 2, .. })
+        ));
+
+        let client = ScriptedClient::new(vec![status(200)]);
+        let result = uploader(policy.clone(), Some(storage.clone()))
+            .send_stored(&client, 10)
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(1, client.requests());
+        assert_eq!(vec!["a", "b"], client.sent_names(0));
+        assert!(storage.lease().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn does_not_store_rejected_items() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        let client = ScriptedClient::new(vec![status(400)]);
+        let result = uploader(fast_policy(), Some(storage.clone()))
+            .send(&client, envelopes(&["a"]))
+            .await;
+        assert!(result.is_err());
+        assert!(storage.lease().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_stored_items_if_still_undeliverable() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        storage.store(b"[{\"name\":\"a\"}]").unwrap();
+        storage.store(b"[{\"name\":\"b\"}]").unwrap();
+
+        let client = ScriptedClient::new(vec![]);
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            ..fast_policy()
+        };
+        let result = uploader(policy.clone(), Some(storage.clone()))
+            .send_stored(&client, 10)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(1, client.requests());
+
+        let client = ScriptedClient::new(vec![status(200), status(200)]);
+        let result = uploader(policy.clone(), Some(storage.clone()))
+            .send_stored(&client, 10)
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(vec!["a"], client.sent_names(0));
+        assert_eq!(vec!["b"], client.sent_names(1));
+    }
+
+    #[tokio::test]
+    async fn keeps_items_if_unauthorized() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        let client = ScriptedClient::new(vec![status(401)]);
+        let result = uploader(fast_policy(), Some(storage.clone()))
+            .send(&client, envelopes(&["a"]))
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::SavedToOfflineStorage { items: 1, .. })
+        ));
+
+        let client = ScriptedClient::new(vec![status(401)]);
+        let result = uploader(fast_policy(), Some(storage.clone()))
+            .send_stored(&client, 10)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(1, client.requests());
+
+        let client = ScriptedClient::new(vec![status(200)]);
+        let result = uploader(fast_policy(), Some(storage.clone()))
+            .send_stored(&client, 10)
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(vec!["a"], client.sent_names(0));
+        assert!(storage.lease().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn skips_corrupt_stored_batches() {
+        let dir = TempDir::new();
+        let storage = Storage::new(dir.0.clone(), StorageLimits::default());
+        storage.store(b"[{\"name\":").unwrap();
+        storage.store(b"[{\"name\":\"a\"}]").unwrap();
+
+        let client = ScriptedClient::new(vec![status(200)]);
+        let result = uploader(fast_policy(), Some(storage.clone()))
+            .send_stored(&client, 10)
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(1, client.requests());
+        assert_eq!(vec!["a"], client.sent_names(0));
+        assert!(storage.lease().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn splits_by_item_count() {
+        let client = ScriptedClient::new((0..3).map(|_| status(200)).collect());
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.batch_limits.max_items = 2;
+        let result = uploader
+            .send(&client, envelopes(&["a", "b", "c", "d", "e"]))
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(3, client.requests());
+        assert_eq!(vec!["a", "b"], client.sent_names(0));
+        assert_eq!(vec!["c", "d"], client.sent_names(1));
+        assert_eq!(vec!["e"], client.sent_names(2));
+    }
+
+    #[tokio::test]
+    async fn splits_by_size() {
+        let item_size = serde_json::to_vec(&envelopes(&["a"])[0]).unwrap().len();
+        let client = ScriptedClient::new((0..3).map(|_| status(200)).collect());
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.batch_limits.max_size = 2 * item_size + 3;
+        let result = uploader
+            .send(&client, envelopes(&["a", "b", "c", "d", "e"]))
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(3, client.requests());
+        for n in 0..3 {
+            let payload_size = client.requests.lock().unwrap()[n].body().len();
+            assert!(payload_size <= uploader.batch_limits.max_size);
+        }
+        assert_eq!(vec!["e"], client.sent_names(2));
+    }
+
+    #[tokio::test]
+    async fn sends_oversized_items_alone() {
+        let client = ScriptedClient::new((0..2).map(|_| status(200)).collect());
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.batch_limits.max_size = 10;
+        let result = uploader.send(&client, envelopes(&["a", "b"])).await;
+
+        assert!(result.is_ok());
+        assert_eq!(vec!["a"], client.sent_names(0));
+        assert_eq!(vec!["b"], client.sent_names(1));
+    }
+
+    #[tokio::test]
+    async fn aggregates_errors_of_all_batches() {
+        let client = ScriptedClient::new(vec![status(400), status(200), status(400)]);
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.batch_limits.max_items = 1;
+        let result = uploader.send(&client, envelopes(&["a", "b", "c"])).await;
+
+        assert_eq!(3, client.requests());
+        let error = result.unwrap_err();
+        assert!(
+            error.to_string().contains("2 of 3 upload requests failed"),
+            "{}",
+            error
+        );
+        assert!(matches!(
+            error,
+            Error::UploadRequests { requests: 3, errors } if errors.len() == 2
+        ));
+    }
+
+    #[tokio::test]
+    async fn does_not_send_empty_batches() {
+        let client = ScriptedClient::new(vec![]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&[]))
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(0, client.requests());
+    }
+
+    #[derive(Debug, Default)]
+    struct SlowClient {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl HttpClient for SlowClient {
+        async fn send(
+            &self,
+            _request: Request<Vec<u8>>,
+        ) -> Result<Response<Bytes>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            Delay::new(Duration::from_millis(20)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(status(200))
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_requests() {
+        let client = SlowClient::default();
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.batch_limits.max_items = 1;
+        uploader.batch_limits.max_concurrent_requests = 2;
+        let result = uploader
+            .send(&client, envelopes(&["a", "b", "c", "d", "e"]))
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(2, client.max_in_flight.load(Ordering::SeqCst));
+    }
+
+    fn redirect(status: u16, location: &str) -> Response<Bytes> {
+        Response::builder()
+            .status(status)
+            .header(http::header::LOCATION, location)
+            .body(Bytes::new())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_and_remembers_them() {
+        let client = ScriptedClient::new(vec![
+            redirect(
+                307,
+                "https://westus-0.in.applicationinsights.azure.com/v2/track",
+            ),
+            redirect(308, "/v2.1/track"),
+            status(200),
+            status(200),
+        ]);
+        let uploader = uploader(fast_policy(), None);
+        assert!(uploader.send(&client, envelopes(&["a"])).await.is_ok());
+        assert!(uploader.send(&client, envelopes(&["b"])).await.is_ok());
+
+        assert_eq!(
+            vec![
+                "http://localhost/v2/track",
+                "https://westus-0.in.applicationinsights.azure.com/v2/track",
+                "https://westus-0.in.applicationinsights.azure.com/v2.1/track",
+                "https://westus-0.in.applicationinsights.azure.com/v2.1/track",
+            ],
+            client.uris()
+        );
+        assert_eq!(vec!["a"], client.sent_names(2));
+    }
+
+    #[tokio::test]
+    async fn forgets_redirects_after_max_age() {
+        let response = Response::builder()
+            .status(307)
+            .header(http::header::LOCATION, "http://regional/v2/track")
+            .header(http::header::CACHE_CONTROL, "private, max-age=0")
+            .body(Bytes::new())
+            .unwrap();
+        let client = ScriptedClient::new(vec![response, status(200), status(200)]);
+        let uploader = uploader(fast_policy(), None);
+        assert!(uploader.send(&client, envelopes(&["a"])).await.is_ok());
+        assert!(uploader.send(&client, envelopes(&["b"])).await.is_ok());
+
+        assert_eq!(
+            vec![
+                "http://localhost/v2/track",
+                "http://regional/v2/track",
+                "http://localhost/v2/track",
+            ],
+            client.uris()
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_redirect_loops() {
+        let client = ScriptedClient::new(vec![
+            redirect(307, "http://regional/v2/track"),
+            redirect(307, "http://localhost/v2/track"),
+            status(200),
+        ]);
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::UploadRedirect { location: Some(location) })
+                if location == "http://localhost/v2/track"
+        ));
+        assert_eq!(2, client.requests());
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_redirects() {
+        let client = ScriptedClient::new(
+            (0..20)
+                .map(|n| redirect(307, &format!("http://regional-{}/v2/track", n)))
+                .collect(),
+        );
+        let result = uploader(fast_policy(), None)
+            .send(&client, envelopes(&["a"]))
+            .await;
+
+        assert!(matches!(result, Err(Error::UploadRedirect { .. })));
+        assert_eq!(MAX_REDIRECTS + 1, client.requests());
+    }
+
+    #[tokio::test]
+    async fn rejects_redirects_without_valid_location() {
+        let client = ScriptedClient::new(vec![status(308), redirect(308, "ftp://regional/")]);
+        let uploader = uploader(fast_policy(), None);
+        for _ in 0..2 {
+            let result = uploader.send(&client, envelopes(&["a"])).await;
+            assert!(matches!(result, Err(Error::UploadRedirect { .. })));
+        }
+        assert_eq!(2, client.requests());
+    }
+
+    #[tokio::test]
+    async fn refetches_token_when_rejected() {
+        let client = ScriptedClient::new(vec![status(200), status(401), status(200)]);
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.credential = Some(CachedCredential::new(Box::new(CountingCredential::new(
+            Duration::from_secs(60 * 60),
+        ))));
+
+        assert!(uploader.send(&client, envelopes(&["a"])).await.is_ok());
+        assert!(uploader.send(&client, envelopes(&["b"])).await.is_ok());
+        assert_eq!(
+            vec!["Bearer token-0", "Bearer token-0", "Bearer token-1"],
+            client.authorizations()
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_token_only_to_application_insights() {
+        let client = ScriptedClient::new(vec![
+            redirect(
+                307,
+                "https://westus-0.in.applicationinsights.azure.com/v2/track",
+            ),
+            redirect(307, "https://example.com/v2/track"),
+            status(200),
+            status(200),
+        ]);
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.credential = Some(CachedCredential::new(Box::new(CountingCredential::new(
+            Duration::from_secs(60 * 60),
+        ))));
+
+        assert!(uploader.send(&client, envelopes(&["a"])).await.is_ok());
+        assert!(uploader.send(&client, envelopes(&["b"])).await.is_ok());
+        assert_eq!(
+            vec!["Bearer token-0", "Bearer token-0", "", ""],
+            client.authorizations()
+        );
+        assert_eq!("https://example.com/v2/track", client.uris()[3]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_if_new_token_is_rejected_too() {
+        let client = ScriptedClient::new(vec![status(403), status(403), status(200)]);
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.credential = Some(CachedCredential::new(Box::new(CountingCredential::new(
+            Duration::from_secs(60 * 60),
+        ))));
+
+        assert!(uploader.send(&client, envelopes(&["a"])).await.is_err());
+        assert_eq!(2, client.requests());
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compresses_payload() {
+        use std::io::Read as _;
+
+        let client = ScriptedClient::new(vec![status(200)]);
+        let mut uploader = uploader(fast_policy(), None);
+        uploader.compression = Some(flate2::Compression::best());
+        assert!(uploader.send(&client, envelopes(&["a", "b"])).await.is_ok());
+
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(
+            "gzip",
+            requests[0].headers()[http::header::CONTENT_ENCODING]
+        );
+        let mut payload = String::new();
+        flate2::read::GzDecoder::new(requests[0].body().as_slice())
+            .read_to_string(&mut payload)
+            .unwrap();
+        let items: Vec<serde_json::Value> = serde_json::from_str(&payload).unwrap();
+        assert_eq!(2, items.len());
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let seconds = Response::builder()
+            .header(http::header::RETRY_AFTER, "7")
+            .body(Bytes::new())
+            .unwrap();
+        assert_eq!(Some(Duration::from_secs(7)), retry_after(&seconds));
+
+        let past_date = Response::builder()
+            .header(http::header::RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT")
+            .body(Bytes::new())
+            .unwrap();
+        assert_eq!(Some(Duration::from_secs(0)), retry_after(&past_date));
+
+        assert_eq!(None, retry_after(&status(429)));
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let policy = fast_policy();
+        for attempt in 1..20 {
+            let backoff = policy.backoff(attempt);
+            assert!(backoff <= policy.max_backoff);
+        }
+    }
+}